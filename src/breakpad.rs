@@ -0,0 +1,318 @@
+// A parser for Breakpad text symbol files, as published by Mozilla's symbol server.
+// The format is documented at https://chromium.googlesource.com/breakpad/breakpad/+/HEAD/docs/symbol_files.md
+// We only parse the records that we need for symbolication, i.e. `MODULE`, `FILE`, `INLINE_ORIGIN`, `FUNC`, line, `INLINE` and `PUBLIC` records, and skip over the stack unwinding records.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use fxhash::FxHashMap;
+
+use crate::error::{Error, Result};
+use crate::fx_processed_profile::table_address::Address;
+use crate::symbol_source::{SourceFrame, SymbolLookup};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BreakpadModule {
+    pub os: String,
+    pub arch: String,
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BreakpadLine {
+    pub address: Address,
+    pub size: u64,
+    pub line: u32,
+    pub file: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BreakpadInline {
+    pub depth: u32,
+    pub call_line: u32,
+    pub call_file: u32,
+    pub origin: u32,
+    pub ranges: Vec<(Address, u64)>,
+}
+
+impl BreakpadInline {
+    fn contains(&self, address: Address) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, size)| start <= address && address < start + size)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BreakpadFunction {
+    pub address: Address,
+    pub size: u64,
+    pub name: String,
+    pub lines: Vec<BreakpadLine>,
+    pub inlines: Vec<BreakpadInline>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BreakpadPublic {
+    pub address: Address,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct BreakpadSymbols {
+    pub module: Option<BreakpadModule>,
+    pub files: FxHashMap<u32, String>,
+    pub inline_origins: FxHashMap<u32, String>,
+    // Both sorted by address, so that we can binary search them.
+    pub functions: Vec<BreakpadFunction>,
+    pub publics: Vec<BreakpadPublic>,
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+// `FUNC` and `PUBLIC` records may have an optional `m` field, which marks symbols that have been merged by identical code folding.
+fn strip_multiple_marker(s: &str) -> &str {
+    s.strip_prefix("m ").unwrap_or(s)
+}
+
+fn parse_func(rest: &str) -> Option<BreakpadFunction> {
+    let mut fields = strip_multiple_marker(rest).splitn(4, ' ');
+    let address = parse_hex(fields.next()?)?;
+    let size = parse_hex(fields.next()?)?;
+    let _parameter_size = parse_hex(fields.next()?)?;
+    let name = fields.next().unwrap_or("").to_string();
+    Some(BreakpadFunction {
+        address,
+        size,
+        name,
+        lines: vec![],
+        inlines: vec![],
+    })
+}
+
+fn parse_public(rest: &str) -> Option<BreakpadPublic> {
+    let mut fields = strip_multiple_marker(rest).splitn(3, ' ');
+    let address = parse_hex(fields.next()?)?;
+    let _parameter_size = parse_hex(fields.next()?)?;
+    let name = fields.next().unwrap_or("").to_string();
+    Some(BreakpadPublic { address, name })
+}
+
+fn parse_inline(rest: &str) -> Option<BreakpadInline> {
+    let mut fields = rest.split(' ');
+    let depth = fields.next()?.parse().ok()?;
+    let call_line = fields.next()?.parse().ok()?;
+    let call_file = fields.next()?.parse().ok()?;
+    let origin = fields.next()?.parse().ok()?;
+    let mut ranges = vec![];
+    while let Some(address) = fields.next() {
+        ranges.push((parse_hex(address)?, parse_hex(fields.next()?)?));
+    }
+    Some(BreakpadInline {
+        depth,
+        call_line,
+        call_file,
+        origin,
+        ranges,
+    })
+}
+
+fn parse_line(line: &str) -> Option<BreakpadLine> {
+    let mut fields = line.split(' ');
+    Some(BreakpadLine {
+        address: parse_hex(fields.next()?)?,
+        size: parse_hex(fields.next()?)?,
+        line: fields.next()?.parse().ok()?,
+        file: fields.next()?.parse().ok()?,
+    })
+}
+
+fn parse_numbered_name(rest: &str) -> Option<(u32, String)> {
+    let (number, name) = rest.split_once(' ')?;
+    Some((number.parse().ok()?, name.to_string()))
+}
+
+impl BreakpadSymbols {
+    pub fn from_path(path: &Path) -> Result<BreakpadSymbols> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(|file| BreakpadSymbols::parse(BufReader::new(file)))
+            .map_err(|e| e.with_path(path))
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<BreakpadSymbols> {
+        let mut symbols = BreakpadSymbols::default();
+        // Line and inline records belong to the most recent `FUNC` record.
+        let mut in_function = false;
+
+        for (ix, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end();
+            let line_number = ix + 1;
+            let (record, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let parsed = match record {
+                "MODULE" => {
+                    let mut fields = rest.splitn(4, ' ');
+                    symbols.module = Some(BreakpadModule {
+                        os: fields.next().unwrap_or("").to_string(),
+                        arch: fields.next().unwrap_or("").to_string(),
+                        id: fields.next().unwrap_or("").to_string(),
+                        name: fields.next().unwrap_or("").to_string(),
+                    });
+                    in_function = false;
+                    Some(())
+                }
+                "FILE" => parse_numbered_name(rest).map(|(number, name)| {
+                    symbols.files.insert(number, name);
+                    in_function = false;
+                }),
+                "INLINE_ORIGIN" => parse_numbered_name(rest).map(|(number, name)| {
+                    symbols.inline_origins.insert(number, name);
+                    in_function = false;
+                }),
+                "FUNC" => parse_func(rest).map(|function| {
+                    symbols.functions.push(function);
+                    in_function = true;
+                }),
+                "PUBLIC" => parse_public(rest).map(|public| {
+                    symbols.publics.push(public);
+                    in_function = false;
+                }),
+                "INLINE" if in_function => parse_inline(rest).map(|inline| {
+                    symbols.functions.last_mut().unwrap().inlines.push(inline);
+                }),
+                "STACK" | "INFO" => {
+                    in_function = false;
+                    Some(())
+                }
+                "" => Some(()),
+                _ if in_function => parse_line(line).map(|l| {
+                    symbols.functions.last_mut().unwrap().lines.push(l);
+                }),
+                _ => None,
+            };
+            if parsed.is_none() {
+                return Err(Error::InvalidSymbolFile {
+                    path: None,
+                    line: line_number,
+                    record: line.to_string(),
+                });
+            }
+        }
+
+        symbols.functions.sort_by_key(|f| f.address);
+        symbols.publics.sort_by_key(|p| p.address);
+        Ok(symbols)
+    }
+
+    pub fn symbol_count(&self) -> usize {
+        self.functions.len() + self.publics.len()
+    }
+
+    fn find_function(&self, address: Address) -> Option<&BreakpadFunction> {
+        let ix = match self.functions.binary_search_by_key(&address, |f| f.address) {
+            Ok(ix) => ix,
+            Err(0) => return None,
+            Err(ix) => ix - 1,
+        };
+        let function = &self.functions[ix];
+        if address < function.address + function.size {
+            Some(function)
+        } else {
+            None
+        }
+    }
+
+    fn find_public(&self, address: Address) -> Option<&BreakpadPublic> {
+        match self.publics.binary_search_by_key(&address, |p| p.address) {
+            Ok(ix) => Some(&self.publics[ix]),
+            Err(0) => None,
+            Err(ix) => Some(&self.publics[ix - 1]),
+        }
+    }
+
+    /// Resolve a relative address (such as `NativeSymbolTableEntry.address`) to a function, its file and line, and the chain of functions that have been inlined at that address.
+    /// `PUBLIC` records are used when there is no `FUNC` record covering the address, in which case we only know the symbol name.
+    pub fn lookup(&self, address: Address) -> Option<SymbolLookup> {
+        let function = match self.find_function(address) {
+            Some(f) => f,
+            None => {
+                // Publics have no size, so they extend to the next public, or the next function, whichever is first.
+                let public = self.find_public(address)?;
+                let next_function = self
+                    .functions
+                    .partition_point(|f| f.address <= public.address);
+                if let Some(f) = self.functions.get(next_function) {
+                    if f.address <= address {
+                        return None;
+                    }
+                }
+                return Some(SymbolLookup {
+                    name: public.name.clone(),
+                    address: public.address,
+                    size: None,
+                    start_line: None,
+                    frames: vec![SourceFrame {
+                        function: public.name.clone(),
+                        file: None,
+                        line: None,
+                    }],
+                });
+            }
+        };
+
+        let file_name = |file: u32| self.files.get(&file).cloned();
+        let line_at = |address: Address| {
+            function
+                .lines
+                .iter()
+                .find(|l| l.address <= address && address < l.address + l.size)
+        };
+        let line = line_at(address);
+
+        // The inlines at this address, from the outermost (depth 0) inwards.
+        let mut inlines: Vec<&BreakpadInline> = function
+            .inlines
+            .iter()
+            .filter(|i| i.contains(address))
+            .collect();
+        inlines.sort_by_key(|i| i.depth);
+
+        // Each inline record gives us the name of the callee, and the location of the call in its caller, so the location of each frame comes from the next inline record in, and the innermost frame's location comes from the line record.
+        let mut names = vec![function.name.clone()];
+        let mut locations = vec![];
+        for inline in &inlines {
+            names.push(
+                self.inline_origins
+                    .get(&inline.origin)
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+            locations.push((file_name(inline.call_file), Some(inline.call_line)));
+        }
+        locations.push((line.and_then(|l| file_name(l.file)), line.map(|l| l.line)));
+
+        let frames = names
+            .into_iter()
+            .zip(locations)
+            .rev()
+            .map(|(function, (file, line))| SourceFrame {
+                function,
+                file,
+                line,
+            })
+            .collect();
+
+        Some(SymbolLookup {
+            name: function.name.clone(),
+            address: function.address,
+            size: Some(function.size),
+            start_line: line_at(function.address).map(|l| l.line),
+            frames,
+        })
+    }
+}
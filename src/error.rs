@@ -0,0 +1,269 @@
+// The errors that can occur while loading, symbolicating and converting profiles.
+// Errors keep the context that a user needs to act on them (such as the file or library involved), so that a batch job can report why each profile or library failed, rather than panicking.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+use crate::fx_processed_profile::validation::TableDiagnostic;
+use crate::fx_processed_profile::WeightType;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// A profile could not be parsed (or serialized) as JSON.
+    Json {
+        path: Option<PathBuf>,
+        source: serde_json::Error,
+    },
+    /// The tables of a profile refer to each other inconsistently, e.g. a stack that points past the end of the frame table.
+    InconsistentTables(Vec<TableDiagnostic>),
+    /// A Breakpad symbol file contains a record that we can't parse.
+    InvalidSymbolFile {
+        path: Option<PathBuf>,
+        line: usize,
+        record: String,
+    },
+    InvalidUrl(url::ParseError),
+    /// The request to a symbol server failed before we got a response.
+    Http(reqwest::Error),
+    /// A library's debug name or breakpad ID, which come from a profile, can't be used to locate its files on a symbol server or in our cache, e.g. because they would lead out of the cache directory.
+    InvalidSymbolFileLocation {
+        debug_name: String,
+        breakpad_id: String,
+        file_name: String,
+    },
+    /// The symbol server responded, but not with the file that we asked for.
+    SymbolFetch {
+        url: Url,
+        status: reqwest::StatusCode,
+    },
+    /// wholesym could not load symbols from a binary or debug file.
+    Symbols(wholesym::Error),
+    /// A weighted profile given to merge, either on the command line or in a manifest, isn't of the form `[<weight>,]<path>`.
+    InvalidMergeInput {
+        path: Option<PathBuf>,
+        line: Option<usize>,
+        input: String,
+    },
+    /// A build to filter profiles by was given an ID that isn't a debug ID.
+    InvalidBuildId(String),
+    /// A binary that was given as the build to filter profiles by has neither a debug ID nor a code ID.
+    MissingBuildId {
+        path: PathBuf,
+    },
+    /// A selector of the threads to keep samples from isn't of the form `<property>=<value>[,...]`, or has a property or process type that we don't know of.
+    InvalidThreadSelector(String),
+    /// A range of time to aggregate samples from isn't of the form `<start>..<end>`, or (for a profiler URL's committed ranges) `<start><unit><duration>`.
+    InvalidTimeRange(String),
+    /// None of the profiles given to merge could be merged.
+    NoProfiles,
+    /// Profiles given to merge without a build to keep are of different builds of a library, whose addresses can't be mixed.
+    MixedBuilds {
+        path: PathBuf,
+        library: String,
+        breakpad_ids: (String, String),
+    },
+    /// A thread's samples are weighted by something other than sample counts, and the weight policy rejects them.
+    UnsupportedWeightType {
+        thread: usize,
+        weight_type: WeightType,
+    },
+    /// None of our symbol sources had debug information for a library. Each failed attempt is kept, in the order that they were made.
+    MissingDebugFile {
+        library: String,
+        breakpad_id: String,
+        attempts: Vec<Error>,
+    },
+}
+
+impl Error {
+    /// Attach a path to IO, JSON, symbol file and manifest errors that don't already have one.
+    pub fn with_path<P: AsRef<Path>>(self, new_path: P) -> Error {
+        let fill = |path: Option<PathBuf>| path.or_else(|| Some(new_path.as_ref().to_path_buf()));
+        match self {
+            Error::Io { path, source } => Error::Io {
+                path: fill(path),
+                source,
+            },
+            Error::Json { path, source } => Error::Json {
+                path: fill(path),
+                source,
+            },
+            Error::InvalidSymbolFile { path, line, record } => Error::InvalidSymbolFile {
+                path: fill(path),
+                line,
+                record,
+            },
+            Error::InvalidMergeInput { path, line, input } => Error::InvalidMergeInput {
+                path: fill(path),
+                line,
+                input,
+            },
+            e => e,
+        }
+    }
+}
+
+fn describe_path(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!(" {}", path.display()),
+        None => String::new(),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => {
+                write!(f, "IO error for{}: {}", describe_path(path), source)
+            }
+            Error::Json { path, source } => {
+                write!(f, "Invalid JSON in{}: {}", describe_path(path), source)
+            }
+            Error::InconsistentTables(diagnostics) => {
+                write!(f, "Inconsistent profile tables")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n\t{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::InvalidSymbolFile { path, line, record } => write!(
+                f,
+                "Invalid breakpad record in{} on line {}: {}",
+                describe_path(path),
+                line,
+                record
+            ),
+            Error::InvalidMergeInput { path, line, input } => {
+                write!(f, "Invalid profile to merge")?;
+                if path.is_some() {
+                    write!(f, " in{}", describe_path(path))?;
+                }
+                if let Some(line) = line {
+                    write!(f, " on line {}", line)?;
+                }
+                write!(f, ": {} (expected [<weight>,]<path>)", input)
+            }
+            Error::InvalidBuildId(id) => write!(f, "Invalid debug ID for a build: {}", id),
+            Error::MissingBuildId { path } => {
+                write!(f, "Could not find a build ID in {}", path.display())
+            }
+            Error::InvalidThreadSelector(selector) => write!(
+                f,
+                "Invalid thread selector: {} (expected <property>=<value>[,...])",
+                selector
+            ),
+            Error::InvalidTimeRange(range) => write!(f, "Invalid time range: {}", range),
+            Error::NoProfiles => write!(f, "None of the input profiles could be merged"),
+            Error::MixedBuilds {
+                path,
+                library,
+                breakpad_ids: (merged, found),
+            } => write!(
+                f,
+                "{} is of build {} of {}, but the profiles before it are of build {}; choose a build to merge with --build-id or --build-binary",
+                path.display(),
+                found,
+                library,
+                merged
+            ),
+            Error::UnsupportedWeightType {
+                thread,
+                weight_type,
+            } => {
+                let weight_type = match weight_type {
+                    WeightType::Samples => "samples",
+                    WeightType::TracingMs => "tracing-ms",
+                    WeightType::Bytes => "bytes",
+                };
+                write!(
+                    f,
+                    "The samples of thread {} are weighted by {} rather than by sample counts",
+                    thread, weight_type
+                )
+            }
+            Error::InvalidUrl(e) => write!(f, "Invalid symbol server url: {}", e),
+            Error::Http(e) => write!(f, "Symbol server request failed: {}", e),
+            Error::InvalidSymbolFileLocation {
+                debug_name,
+                breakpad_id,
+                file_name,
+            } => write!(
+                f,
+                "Invalid symbol file location: debug name {:?}, breakpad ID {:?}, file name {:?}",
+                debug_name, breakpad_id, file_name
+            ),
+            Error::SymbolFetch { url, status } => {
+                write!(f, "Symbol server returned {} for {}", status, url)
+            }
+            Error::Symbols(e) => write!(f, "Could not load symbols: {}", e),
+            Error::MissingDebugFile {
+                library,
+                breakpad_id,
+                attempts,
+            } => {
+                write!(
+                    f,
+                    "No debug information found for {} {}",
+                    library, breakpad_id
+                )?;
+                for attempt in attempts {
+                    write!(f, "\n\t{}", attempt)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            Error::InvalidUrl(e) => Some(e),
+            Error::Http(e) => Some(e),
+            Error::Symbols(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(source: serde_json::Error) -> Error {
+        Error::Json { path: None, source }
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Error {
+        Error::InvalidUrl(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Http(e)
+    }
+}
+
+impl From<wholesym::Error> for Error {
+    fn from(e: wholesym::Error) -> Error {
+        Error::Symbols(e)
+    }
+}
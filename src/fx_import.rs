@@ -1,8 +1,53 @@
+// Loading processed profiles from disk.
+// Profiles that are uploaded to, or downloaded from, the Firefox Profiler are gzip-compressed (`.json.gz`), while profiles saved from the profiler are usually plain JSON, so we accept both, and tell them apart by their contents rather than their file extension.
 
 use std::fs::File;
-use fxprof_processed_profile::Profile;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
 
-fn import_profile<F:Into<File>>(profile_filepath: F) -> Option<Profile> {
+use flate2::bufread::MultiGzDecoder;
 
-    None
+use crate::error::{Error, Result};
+use crate::fx_processed_profile::validation::validate_profile;
+use crate::fx_processed_profile::Profile;
+
+// Every gzip stream starts with these two bytes (see RFC 1952).
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Check whether a buffered stream starts with the gzip magic bytes, without consuming any input.
+pub fn is_gzip<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.starts_with(&GZIP_MAGIC))
+}
+
+/// Parse a profile from a stream of either plain or gzip-compressed JSON.
+/// The profile is decompressed and deserialized as it is read, so we never hold the whole (uncompressed) JSON text in memory.
+/// Profiles with inconsistent tables are rejected here, so that the rest of the crate can look up table entries without checking them.
+pub fn read_profile<R: Read>(reader: R) -> Result<Profile> {
+    let mut reader = BufReader::new(reader);
+    let profile = if is_gzip(&mut reader)? {
+        serde_json::from_reader(BufReader::new(MultiGzDecoder::new(reader)))?
+    } else {
+        serde_json::from_reader(reader)?
+    };
+    check_tables(&profile)?;
+    Ok(profile)
+}
+
+/// Validate the tables of a profile, turning any problems into an error.
+pub fn check_tables(profile: &Profile) -> Result<()> {
+    let diagnostics = validate_profile(profile);
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InconsistentTables(diagnostics))
+    }
+}
+
+/// Load a profile from a `.json` or `.json.gz` file.
+pub fn import_profile<P: AsRef<Path>>(profile_filepath: P) -> Result<Profile> {
+    let path = profile_filepath.as_ref();
+    File::open(path)
+        .map_err(Error::from)
+        .and_then(read_profile)
+        .map_err(|e| e.with_path(path))
 }
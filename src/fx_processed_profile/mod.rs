@@ -1,4 +1,5 @@
 pub mod table_address;
+pub mod validation;
 
 use serde::de;
 use serde::de::{Deserializer, Visitor};
@@ -50,12 +51,38 @@ pub type Microseconds = f64;
 pub type Milliseconds = f64;
 pub type Seconds = f64;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct StartEndRange {
     pub start: Milliseconds,
     pub end: Milliseconds,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PausedReason {
+    /// The user paused the profiler.
+    ProfilerPaused,
+    /// The profiler was paused while it collected the profile.
+    Collecting,
+    #[serde(other)]
+    Other,
+}
+
+/// A span of time in which the profiler wasn't sampling. A missing start means that it was paused before the profile began, and a missing end that it was still paused when the profile ended.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct PausedRange {
+    pub startTime: Option<Milliseconds>,
+    pub endTime: Option<Milliseconds>,
+    pub reason: PausedReason,
+}
+
+impl PausedRange {
+    pub fn contains(&self, time: Milliseconds) -> bool {
+        self.startTime.is_none_or(|start| start <= time)
+            && self.endTime.is_none_or(|end| time < end)
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct StackTable {
     pub frame: Array<IndexIntoFrameTable>,
@@ -71,17 +98,59 @@ pub struct StackTableEntry {
     pub prefix: Option<IndexIntoStackTable>,
 }
 
+impl StackTable {
+    pub fn push(&mut self, entry: StackTableEntry) -> IndexIntoStackTable {
+        self.frame.push(entry.frame);
+        self.category.push(entry.category);
+        self.prefix.push(entry.prefix);
+        self.length += 1;
+        (self.length - 1) as IndexIntoStackTable
+    }
+
+    /// Walk a stack from its leaf to its root, following the prefix of each entry.
+    pub fn walk(&self, stack: IndexIntoStackTable) -> StackWalker<'_> {
+        StackWalker {
+            table: self,
+            next: Some(stack),
+            remaining: self.length(),
+        }
+    }
+}
+
+/// An iterator over the entries of a stack, from the leaf to the root.
+/// The walk stops early at a missing stack, and it never takes more steps than there are stacks in the table, so that it terminates on tables with cycles of prefixes.
+pub struct StackWalker<'a> {
+    table: &'a StackTable,
+    next: Option<IndexIntoStackTable>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for StackWalker<'a> {
+    type Item = (IndexIntoStackTable, StackTableEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ix = self.next.take()?;
+        if self.remaining == 0 || ix < 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let entry = self.table.try_lookup(ix as usize)?;
+        self.next = entry.prefix;
+        Some((ix, entry))
+    }
+}
+
 impl TableLookup<StackTableEntry> for StackTable {
     fn length(&self) -> usize {
         self.length as usize
     }
-    fn lookup(&self, ix: usize) -> StackTableEntry {
-        // TODO: At some point, we should validate the lengths...
-        StackTableEntry {
-            frame: self.frame[ix],
-            category: self.category[ix],
-            prefix: self.prefix[ix],
-        }
+    fn try_lookup(&self, ix: usize) -> Option<StackTableEntry> {
+        // Column lengths are checked by `validation::validate_profile` when a profile is loaded, but we may still be given a table that hasn't been validated.
+        Some(StackTableEntry {
+            frame: *self.frame.get(ix)?,
+            category: *self.category.get(ix)?,
+            prefix: *self.prefix.get(ix)?,
+        })
     }
     fn iter(&self) -> profile_table_iterator::TableIterator<Self, StackTableEntry>
     where
@@ -95,11 +164,27 @@ impl TableLookup<StackTableEntry> for StackTable {
 #[serde(rename_all = "lowercase")]
 pub enum WeightType {
     Samples,
+    #[serde(rename = "tracing-ms")]
     TracingMs,
     Bytes,
 }
 
-type Weight = i64;
+/// Sample counts and sizes are whole numbers, but traced durations are fractional milliseconds, so weights are kept in whichever form they were written in.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Weight {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Weight {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Weight::Integer(weight) => weight as f64,
+            Weight::Float(weight) => weight,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SamplesLikeTableShape {
@@ -118,7 +203,7 @@ pub struct SamplesTable {
     pub time: Array<Milliseconds>,
     pub weight: Option<Array<Weight>>,
     pub weightType: WeightType,
-    pub threadCPUDelta: Option<ArrayQ<i32>>,
+    pub threadCPUDelta: Option<ArrayQ<i64>>,
     pub threadId: Option<Array<Tid>>,
     pub length: u32,
 }
@@ -131,7 +216,7 @@ pub struct SampleTableEntry {
     pub time: Milliseconds,
     pub weight: Option<Weight>,
     pub weightType: WeightType,
-    pub threadCPUDelta: Option<i32>,
+    pub threadCPUDelta: Option<i64>,
     pub threadId: Option<Tid>,
 }
 
@@ -139,17 +224,33 @@ impl TableLookup<SampleTableEntry> for SamplesTable {
     fn length(&self) -> usize {
         self.length as usize
     }
-    fn lookup(&self, ix: usize) -> SampleTableEntry {
-        SampleTableEntry {
-            responsiveness: self.responsiveness.as_ref().and_then(|a| a[ix]),
-            eventDelay: self.eventDelay.as_ref().and_then(|a| a[ix]),
-            stack: self.stack[ix],
-            time: self.time[ix],
-            weight: self.weight.as_ref().map(|a| a[ix]),
+    fn try_lookup(&self, ix: usize) -> Option<SampleTableEntry> {
+        // Optional columns are only missing a row if they are present at all.
+        Some(SampleTableEntry {
+            responsiveness: match &self.responsiveness {
+                Some(a) => *a.get(ix)?,
+                None => None,
+            },
+            eventDelay: match &self.eventDelay {
+                Some(a) => *a.get(ix)?,
+                None => None,
+            },
+            stack: *self.stack.get(ix)?,
+            time: *self.time.get(ix)?,
+            weight: match &self.weight {
+                Some(a) => Some(*a.get(ix)?),
+                None => None,
+            },
             weightType: self.weightType,
-            threadCPUDelta: self.threadCPUDelta.as_ref().and_then(|a| a[ix]),
-            threadId: self.threadId.as_ref().map(|a| a[ix].clone()),
-        }
+            threadCPUDelta: match &self.threadCPUDelta {
+                Some(a) => *a.get(ix)?,
+                None => None,
+            },
+            threadId: match &self.threadId {
+                Some(a) => Some(a.get(ix)?.clone()),
+                None => None,
+            },
+        })
     }
     fn iter(&self) -> TableIterator<Self, SampleTableEntry>
     where
@@ -198,7 +299,7 @@ pub enum NativeAllocationsTable {
     BalancedNativeAllocationsTable(BalancedNativeAllocationsTable),
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ProcessType {
     Default,
@@ -243,23 +344,40 @@ pub struct FrameTableEntry {
     pub column: Option<u32>,
 }
 
+impl FrameTable {
+    pub fn push(&mut self, entry: FrameTableEntry) -> IndexIntoFrameTable {
+        self.address.push(entry.address);
+        self.inlineDepth.push(entry.inlineDepth);
+        self.category.push(entry.category);
+        self.subcategory.push(entry.subcategory);
+        self.func.push(entry.func);
+        self.nativeSymbol.push(entry.nativeSymbol);
+        self.innerWindowID.push(entry.innerWindowID);
+        self.implementation.push(entry.implementation);
+        self.line.push(entry.line);
+        self.column.push(entry.column);
+        self.length += 1;
+        (self.length - 1) as IndexIntoFrameTable
+    }
+}
+
 impl TableLookup<FrameTableEntry> for FrameTable {
     fn length(&self) -> usize {
         self.length as usize
     }
-    fn lookup(&self, ix: usize) -> FrameTableEntry {
-        FrameTableEntry {
-            address: self.address[ix],
-            inlineDepth: self.inlineDepth[ix],
-            category: self.category[ix],
-            subcategory: self.subcategory[ix],
-            func: self.func[ix],
-            nativeSymbol: self.nativeSymbol[ix],
-            innerWindowID: self.innerWindowID[ix],
-            implementation: self.implementation[ix],
-            line: self.line[ix],
-            column: self.column[ix],
-        }
+    fn try_lookup(&self, ix: usize) -> Option<FrameTableEntry> {
+        Some(FrameTableEntry {
+            address: *self.address.get(ix)?,
+            inlineDepth: *self.inlineDepth.get(ix)?,
+            category: *self.category.get(ix)?,
+            subcategory: *self.subcategory.get(ix)?,
+            func: *self.func.get(ix)?,
+            nativeSymbol: *self.nativeSymbol.get(ix)?,
+            innerWindowID: *self.innerWindowID.get(ix)?,
+            implementation: *self.implementation.get(ix)?,
+            line: *self.line.get(ix)?,
+            column: *self.column.get(ix)?,
+        })
     }
     fn iter(&self) -> TableIterator<Self, FrameTableEntry>
     where
@@ -281,12 +399,60 @@ pub struct FuncTable {
     pub length: u32,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FuncTableEntry {
+    pub name: IndexIntoStringTable,
+    pub isJS: bool,
+    pub relevantForJS: bool,
+    pub resource: IndexIntoResourceTable,
+    pub fileName: Option<IndexIntoStringTable>,
+    pub lineNumber: Option<u32>,
+    pub columnNumber: Option<u32>,
+}
+
+impl FuncTable {
+    pub fn push(&mut self, entry: FuncTableEntry) -> IndexIntoFuncTable {
+        self.name.push(entry.name);
+        self.isJS.push(entry.isJS);
+        self.relevantForJS.push(entry.relevantForJS);
+        self.resource.push(entry.resource);
+        self.fileName.push(entry.fileName);
+        self.lineNumber.push(entry.lineNumber);
+        self.columnNumber.push(entry.columnNumber);
+        self.length += 1;
+        (self.length - 1) as IndexIntoFuncTable
+    }
+}
+
+impl TableLookup<FuncTableEntry> for FuncTable {
+    fn length(&self) -> usize {
+        self.length as usize
+    }
+    fn try_lookup(&self, ix: usize) -> Option<FuncTableEntry> {
+        Some(FuncTableEntry {
+            name: *self.name.get(ix)?,
+            isJS: *self.isJS.get(ix)?,
+            relevantForJS: *self.relevantForJS.get(ix)?,
+            resource: *self.resource.get(ix)?,
+            fileName: *self.fileName.get(ix)?,
+            lineNumber: *self.lineNumber.get(ix)?,
+            columnNumber: *self.columnNumber.get(ix)?,
+        })
+    }
+    fn iter(&self) -> TableIterator<Self, FuncTableEntry>
+    where
+        Self: Sized,
+    {
+        TableIterator::from(self)
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct NativeSymbolTable {
     pub libIndex: Array<IndexIntoLibs>,
     pub address: Array<Address>,
     pub name: Array<IndexIntoStringTable>,
-    pub functionSize: ArrayQ<u8>,
+    pub functionSize: ArrayQ<u32>,
     pub length: u32,
 }
 
@@ -295,20 +461,31 @@ pub struct NativeSymbolTableEntry {
     pub libIndex: IndexIntoLibs,
     pub address: Address,
     pub name: IndexIntoStringTable,
-    pub functionSize: Option<u8>,
+    pub functionSize: Option<u32>,
+}
+
+impl NativeSymbolTable {
+    pub fn push(&mut self, entry: NativeSymbolTableEntry) -> IndexIntoNativeSymbolTable {
+        self.libIndex.push(entry.libIndex);
+        self.address.push(entry.address);
+        self.name.push(entry.name);
+        self.functionSize.push(entry.functionSize);
+        self.length += 1;
+        (self.length - 1) as IndexIntoNativeSymbolTable
+    }
 }
 
 impl TableLookup<NativeSymbolTableEntry> for NativeSymbolTable {
     fn length(&self) -> usize {
         self.length as usize
     }
-    fn lookup(&self, ix: usize) -> NativeSymbolTableEntry {
-        NativeSymbolTableEntry {
-            libIndex: self.libIndex[ix],
-            address: self.address[ix],
-            name: self.name[ix],
-            functionSize: self.functionSize[ix],
-        }
+    fn try_lookup(&self, ix: usize) -> Option<NativeSymbolTableEntry> {
+        Some(NativeSymbolTableEntry {
+            libIndex: *self.libIndex.get(ix)?,
+            address: *self.address.get(ix)?,
+            name: *self.name.get(ix)?,
+            functionSize: *self.functionSize.get(ix)?,
+        })
     }
     fn iter(&self) -> TableIterator<Self, NativeSymbolTableEntry>
     where
@@ -328,6 +505,16 @@ pub struct ResourceTable {
     pub ty: Array<resourceTypeEnum>,
 }
 
+impl ResourceTable {
+    /// Find the first resource that refers to a given library.
+    pub fn resource_for_lib(&self, lib: IndexIntoLibs) -> Option<IndexIntoResourceTable> {
+        self.lib
+            .iter()
+            .position(|l| *l == Some(lib))
+            .map(|ix| TableAddress::Address(ix as Address))
+    }
+}
+
 // This is a lot simpler than the JS implementation, but lacks the "reverse lookup" optimisation
 pub type UniqueStringArray = Array<String>;
 
@@ -338,8 +525,8 @@ pub struct Thread {
     pub processShutdownTime: Option<Milliseconds>,
     pub registerTime: Option<Milliseconds>,
     pub unregisterTime: Option<Milliseconds>,
-    // TODO: Implement:
-    // pub pausedRanges: Array<PausedRange>,
+    #[serde(default)]
+    pub pausedRanges: Array<PausedRange>,
     pub name: String,
     pub isMainThread: bool,
     // TODO: Implement
@@ -362,7 +549,7 @@ pub struct Thread {
     #[serde(alias = "stringTable")]
     pub stringTable: UniqueStringArray,
     pub funcTable: FuncTable,
-    // pub resourceTable: ResourceTable,
+    pub resourceTable: ResourceTable,
     pub nativeSymbols: NativeSymbolTable,
     // pub jsTracer: Option<JsTracerTable>,
     pub isPrivateBrowsing: Option<bool>,
@@ -380,10 +567,76 @@ pub struct Lib {
     pub codeId: Option<String>,
 }
 
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Category {
+    pub name: String,
+    pub color: String,
+    pub subcategories: Array<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ThreadCPUDeltaUnit {
+    #[serde(rename = "ns")]
+    Nanoseconds,
+    #[serde(rename = "µs")]
+    Microseconds,
+    #[serde(rename = "variable CPU cycles")]
+    VariableCPUCycles,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SampleUnits {
+    pub time: String,
+    pub eventDelay: String,
+    pub threadCPUDelta: ThreadCPUDeltaUnit,
+}
+
+// Many of the meta fields are optional, and are omitted (rather than null) when they are missing, so we skip them when serializing in order to round-trip profiles faithfully.
+// Fields that we don't use (such as the marker schema) are kept as they were in `other`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ProfileMeta {
+    pub interval: Milliseconds,
+    pub startTime: Milliseconds,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endTime: Option<Milliseconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profilingStartTime: Option<Milliseconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profilingEndTime: Option<Milliseconds>,
+    pub processType: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Array<Category>>,
+    pub product: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stackwalk: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<bool>,
+    pub version: i64,
+    pub preprocessedProfileVersion: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abi: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oscpu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolkit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appBuildID: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbolicated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampleUnits: Option<SampleUnits>,
+    /// The times at which the profiler was paused in every thread.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pausedRanges: Option<Array<PausedRange>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Profile {
-    // TODO: Implement
-    // pub meta: serde_json::Value,
+    pub meta: ProfileMeta,
     pub libs: Array<Lib>,
     pub pages: serde_json::Value,
     // pub counters: serde_json::Value,
@@ -12,7 +12,7 @@ use std::fmt;
 
 pub type Address = u64;
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum TableAddress {
     Address(Address),
     Base,
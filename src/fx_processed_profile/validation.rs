@@ -0,0 +1,389 @@
+// Consistency checks for the tables of a processed profile.
+// The tables are stored as columns, which refer to each other by index, and our `TableLookup` implementations index into them directly. A malformed profile would therefore cause a panic part way through transposition or symbolication, so we check them all up-front instead, and report every problem that we find.
+
+use std::fmt;
+
+use super::table_address::TableAddress;
+use super::{Profile, Thread};
+
+/// A single problem with one of a thread's tables.
+/// `column` and `row` are given where the problem can be pinned down to them.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableDiagnostic {
+    pub thread: usize,
+    pub table: &'static str,
+    pub column: Option<&'static str>,
+    pub row: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for TableDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread {}: {}", self.thread, self.table)?;
+        if let Some(column) = self.column {
+            write!(f, ".{}", column)?;
+        }
+        if let Some(row) = self.row {
+            write!(f, "[{}]", row)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+struct ThreadValidator {
+    thread: usize,
+    diagnostics: Vec<TableDiagnostic>,
+}
+
+impl ThreadValidator {
+    fn report(
+        &mut self,
+        table: &'static str,
+        column: Option<&'static str>,
+        row: Option<usize>,
+        message: String,
+    ) {
+        self.diagnostics.push(TableDiagnostic {
+            thread: self.thread,
+            table,
+            column,
+            row,
+            message,
+        });
+    }
+
+    fn check_length(
+        &mut self,
+        table: &'static str,
+        column: &'static str,
+        actual: usize,
+        length: usize,
+    ) {
+        if actual != length {
+            self.report(
+                table,
+                Some(column),
+                None,
+                format!(
+                    "has {} entries, but the table has a length of {}",
+                    actual, length
+                ),
+            );
+        }
+    }
+
+    // Optional columns are only checked when they are present.
+    fn check_optional_length<T>(
+        &mut self,
+        table: &'static str,
+        column: &'static str,
+        values: &Option<Vec<T>>,
+        length: usize,
+    ) {
+        if let Some(values) = values {
+            self.check_length(table, column, values.len(), length);
+        }
+    }
+
+    /// Check that every index in a column refers to a row of the target table.
+    fn check_indices<'a, I: IntoIterator<Item = &'a i64>>(
+        &mut self,
+        table: &'static str,
+        column: &'static str,
+        indices: I,
+        target: &'static str,
+        target_length: usize,
+    ) {
+        for (row, &ix) in indices.into_iter().enumerate() {
+            if ix < 0 || ix as usize >= target_length {
+                self.report(
+                    table,
+                    Some(column),
+                    Some(row),
+                    format!(
+                        "{} is not a valid index into the {} (length {})",
+                        ix, target, target_length
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_optional_indices<'a, I: IntoIterator<Item = &'a Option<i64>>>(
+        &mut self,
+        table: &'static str,
+        column: &'static str,
+        indices: I,
+        target: &'static str,
+        target_length: usize,
+    ) {
+        for (row, ix) in indices.into_iter().enumerate() {
+            if let Some(ix) = ix {
+                if *ix < 0 || *ix as usize >= target_length {
+                    self.report(
+                        table,
+                        Some(column),
+                        Some(row),
+                        format!(
+                            "{} is not a valid index into the {} (length {})",
+                            ix, target, target_length
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Every stack's chain of prefixes must end at a root stack, rather than looping back on itself.
+    /// Each stack is visited at most twice, as we stop walking a chain as soon as we reach a stack that we have already checked.
+    fn check_prefix_cycles(&mut self, thread: &Thread) {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Unvisited,
+            InChain,
+            Done,
+        }
+        let prefixes = &thread.stackTable.prefix;
+        let mut states = vec![State::Unvisited; prefixes.len()];
+        for start in 0..prefixes.len() {
+            let mut chain = vec![];
+            let mut current = Some(start);
+            while let Some(ix) = current {
+                match states.get(ix) {
+                    Some(State::Unvisited) => {
+                        states[ix] = State::InChain;
+                        chain.push(ix);
+                        current = prefixes[ix]
+                            .filter(|&prefix| prefix >= 0)
+                            .map(|prefix| prefix as usize);
+                    }
+                    Some(State::InChain) => {
+                        self.report(
+                            "stackTable",
+                            Some("prefix"),
+                            Some(ix),
+                            "is part of a cycle of prefixes".to_string(),
+                        );
+                        break;
+                    }
+                    // Out of range prefixes have already been reported.
+                    Some(State::Done) | None => break,
+                }
+            }
+            for ix in chain {
+                states[ix] = State::Done;
+            }
+        }
+    }
+
+    fn validate(&mut self, thread: &Thread, lib_count: usize) {
+        let strings = thread.stringTable.len();
+
+        let samples = &thread.samples;
+        let length = samples.length as usize;
+        self.check_length("samples", "stack", samples.stack.len(), length);
+        self.check_length("samples", "time", samples.time.len(), length);
+        self.check_optional_length("samples", "responsiveness", &samples.responsiveness, length);
+        self.check_optional_length("samples", "eventDelay", &samples.eventDelay, length);
+        self.check_optional_length("samples", "weight", &samples.weight, length);
+        self.check_optional_length("samples", "threadCPUDelta", &samples.threadCPUDelta, length);
+        self.check_optional_length("samples", "threadId", &samples.threadId, length);
+        self.check_optional_indices(
+            "samples",
+            "stack",
+            &samples.stack,
+            "stackTable",
+            thread.stackTable.length as usize,
+        );
+
+        let stacks = &thread.stackTable;
+        let length = stacks.length as usize;
+        self.check_length("stackTable", "frame", stacks.frame.len(), length);
+        self.check_length("stackTable", "category", stacks.category.len(), length);
+        self.check_length("stackTable", "prefix", stacks.prefix.len(), length);
+        self.check_indices(
+            "stackTable",
+            "frame",
+            &stacks.frame,
+            "frameTable",
+            thread.frameTable.length as usize,
+        );
+        self.check_optional_indices("stackTable", "prefix", &stacks.prefix, "stackTable", length);
+        self.check_prefix_cycles(thread);
+
+        let frames = &thread.frameTable;
+        let length = frames.length as usize;
+        self.check_length("frameTable", "address", frames.address.len(), length);
+        self.check_length(
+            "frameTable",
+            "inlineDepth",
+            frames.inlineDepth.len(),
+            length,
+        );
+        self.check_length("frameTable", "category", frames.category.len(), length);
+        self.check_length(
+            "frameTable",
+            "subcategory",
+            frames.subcategory.len(),
+            length,
+        );
+        self.check_length("frameTable", "func", frames.func.len(), length);
+        self.check_length(
+            "frameTable",
+            "nativeSymbol",
+            frames.nativeSymbol.len(),
+            length,
+        );
+        self.check_length(
+            "frameTable",
+            "innerWindowID",
+            frames.innerWindowID.len(),
+            length,
+        );
+        self.check_length(
+            "frameTable",
+            "implementation",
+            frames.implementation.len(),
+            length,
+        );
+        self.check_length("frameTable", "line", frames.line.len(), length);
+        self.check_length("frameTable", "column", frames.column.len(), length);
+        self.check_optional_indices(
+            "frameTable",
+            "func",
+            &frames.func,
+            "funcTable",
+            thread.funcTable.length as usize,
+        );
+        self.check_optional_indices(
+            "frameTable",
+            "nativeSymbol",
+            &frames.nativeSymbol,
+            "nativeSymbols",
+            thread.nativeSymbols.length as usize,
+        );
+        self.check_optional_indices(
+            "frameTable",
+            "implementation",
+            &frames.implementation,
+            "stringTable",
+            strings,
+        );
+
+        let funcs = &thread.funcTable;
+        let length = funcs.length as usize;
+        self.check_length("funcTable", "name", funcs.name.len(), length);
+        self.check_length("funcTable", "isJS", funcs.isJS.len(), length);
+        self.check_length(
+            "funcTable",
+            "relevantForJS",
+            funcs.relevantForJS.len(),
+            length,
+        );
+        self.check_length("funcTable", "resource", funcs.resource.len(), length);
+        self.check_length("funcTable", "fileName", funcs.fileName.len(), length);
+        self.check_length("funcTable", "lineNumber", funcs.lineNumber.len(), length);
+        self.check_length(
+            "funcTable",
+            "columnNumber",
+            funcs.columnNumber.len(),
+            length,
+        );
+        self.check_indices("funcTable", "name", &funcs.name, "stringTable", strings);
+        self.check_optional_indices(
+            "funcTable",
+            "fileName",
+            &funcs.fileName,
+            "stringTable",
+            strings,
+        );
+        let resources = thread.resourceTable.length as usize;
+        for (row, resource) in funcs.resource.iter().enumerate() {
+            match resource {
+                TableAddress::Address(ix) if *ix as usize >= resources => self.report(
+                    "funcTable",
+                    Some("resource"),
+                    Some(row),
+                    format!(
+                        "{} is not a valid index into the resourceTable (length {})",
+                        ix, resources
+                    ),
+                ),
+                TableAddress::Error(ix) => self.report(
+                    "funcTable",
+                    Some("resource"),
+                    Some(row),
+                    format!("{} is not a valid index into the resourceTable", ix),
+                ),
+                _ => {}
+            }
+        }
+
+        // Resources are looked up through the func table, so their columns need to be complete too.
+        let resource_table = &thread.resourceTable;
+        self.check_length("resourceTable", "lib", resource_table.lib.len(), resources);
+        self.check_length(
+            "resourceTable",
+            "name",
+            resource_table.name.len(),
+            resources,
+        );
+        self.check_length(
+            "resourceTable",
+            "host",
+            resource_table.host.len(),
+            resources,
+        );
+        self.check_length("resourceTable", "type", resource_table.ty.len(), resources);
+
+        let symbols = &thread.nativeSymbols;
+        let length = symbols.length as usize;
+        self.check_length("nativeSymbols", "libIndex", symbols.libIndex.len(), length);
+        self.check_length("nativeSymbols", "address", symbols.address.len(), length);
+        self.check_length("nativeSymbols", "name", symbols.name.len(), length);
+        self.check_length(
+            "nativeSymbols",
+            "functionSize",
+            symbols.functionSize.len(),
+            length,
+        );
+        self.check_indices(
+            "nativeSymbols",
+            "libIndex",
+            &symbols.libIndex,
+            "libs",
+            lib_count,
+        );
+        self.check_indices(
+            "nativeSymbols",
+            "name",
+            &symbols.name,
+            "stringTable",
+            strings,
+        );
+    }
+}
+
+/// Check a single thread of a profile with `lib_count` libraries.
+pub fn validate_thread(
+    thread_ix: usize,
+    thread: &Thread,
+    lib_count: usize,
+) -> Vec<TableDiagnostic> {
+    let mut validator = ThreadValidator {
+        thread: thread_ix,
+        diagnostics: vec![],
+    };
+    validator.validate(thread, lib_count);
+    validator.diagnostics
+}
+
+/// Check every thread of a profile. An empty list means that all of the tables can be safely looked up.
+pub fn validate_profile(profile: &Profile) -> Vec<TableDiagnostic> {
+    profile
+        .threads
+        .iter()
+        .enumerate()
+        .flat_map(|(ix, thread)| validate_thread(ix, thread, profile.libs.len()))
+        .collect()
+}
@@ -4,7 +4,7 @@ use fx_processed_profile::NativeSymbolTable;
 use fx_processed_profile::NativeSymbolTableEntry;
 use fx_processed_profile::{
     table_address::TableAddress, IndexIntoCategoryList, IndexIntoStackTable, Milliseconds,
-    SamplesTable, FrameTable,
+    SamplesTable, FrameTable, WeightType,
 };
 use std::path::Path;
 
@@ -12,75 +12,90 @@ use profile_table_iterator::TableLookup;
 use serde_json::value::Index;
 use wholesym::{LibraryInfo, SymbolManager, SymbolManagerConfig};
 
+use crate::breakpad::BreakpadSymbols;
 use crate::fx_processed_profile::Lib;
+use crate::libraries::{BuildFilter, LibraryAllowlist};
+use crate::prologue::PrologueClassifier;
+use crate::sample_profile::SampleProfile;
+use crate::symbol_server::SymbolServer;
+use crate::symbol_source::SymbolSource;
+use crate::transposed::{DiscardedSamples, SampleFilter, WeightPolicy};
 use crate::fx_processed_profile::{table_address::Address, IndexIntoFrameTable, StackTable};
 
+pub mod breakpad;
+pub mod error;
+pub mod fx_import;
 pub mod fx_processed_profile;
+pub mod libraries;
+pub mod merge;
 pub mod profile_table_iterator;
+pub mod prologue;
+pub mod sample_profile;
+pub mod symbol_server;
+pub mod symbol_source;
+pub mod symbolication;
+pub mod threads;
+pub mod time_range;
 pub mod transposed;
 
-const MOZILLA_SYMBOL_SERVER: &'static str = "https://symbols.mozilla.org/";
-
-
-pub async fn query_symbol_file_server(filename: &String, uuid: &String) -> Option<()> {
-    // We can call unwrap here "safely" as the URL is static (i.e. we've pre-verified it).
-    let base_url : url::Url = url::Url::parse(MOZILLA_SYMBOL_SERVER).unwrap();
-
-    // We want to make a request like:
-    // https://symbols.mozilla.org/XUL/5B2AE053F0313841AF23AA605E66A6470/XUL.dSYM.tar.bz2
-    let sym_file = filename.clone() + ".dSYM.tar.bz2"; // MacOS Specific!
-    // Construct a url for the request
-    let mut url = base_url.clone();
-    url = url.join(filename.as_str()).ok()?;
-    url = url.join(uuid.as_str()).ok()?;
-    url = url.join(sym_file.as_str()).ok()?;
-
-    let response = reqwest::get(url.clone()).await.ok()?;
-    let status = response.status();
-
-    if !(status.is_redirection() || status.is_success()) {
-        return None;
-    }
-
-    // We have some success, so continue to create a tempfile and download the data.
-    println!("Successfully found symbols at {:?}", url.as_str());
-
-
-    None
-}
-
-// Note, this is currently MacOS specific.
-const MOZILLA_LIBS: [&'static str; 2] = ["XUL", "firefox"];
+pub use error::Error;
 
+/// Find the symbols for a library, trying the binary itself, then its debug files on this machine, and then the symbol server.
+/// Libraries that aren't in the allowlist are skipped, and return `Ok(None)`. If every source fails, the error lists why each of them failed.
 pub async fn find_symbol_map(
     lib: &Lib,
     symbol_manager: &SymbolManager,
-) -> Option<wholesym::SymbolMap> {
+    symbol_server: &SymbolServer,
+    allowlist: &LibraryAllowlist,
+) -> Result<Option<SymbolSource>, Error> {
     // Carry out a multi-staged attaempt to get a symbolmap for this library.
     // Step 1. We only care about libraries that we can *optimise*, i.e.
     // libraries that contain Mozilla source code, so reject all libraries
     // where the name is not in the list of libraries that we care about.
-    let lib_name = lib.name.clone();
-    if ! MOZILLA_LIBS.contains(&lib_name.as_str() ) {
-        return None;
+    if !allowlist.contains(lib) {
+        return Ok(None);
     }
+    let mut attempts = vec![];
+
     // Step 2. See if we can get the info from a simple lookup of the file.
     let symbol_map = symbol_manager
-        .load_symbol_map_for_binary_at_path(
-            Path::new(&lib.path),
-            Some(wholesym::MultiArchDisambiguator::Arch("arm64".into())),
-        )
+        .load_symbol_map_for_binary_at_path(Path::new(&lib.path), libraries::disambiguator(lib))
         .await;
 
     // If it works out, return the map we found
-    if let Ok(map) = symbol_map {
-        return Some(map);
+    match symbol_map {
+        Ok(map) => return Ok(Some(SymbolSource::SymbolMap(map))),
+        Err(e) => attempts.push(Error::from(e)),
+    }
+
+    // Step 2b. Let wholesym look for debug files in the usual places for the library's platform.
+    match symbol_manager
+        .load_symbol_map(&libraries::library_info(lib))
+        .await
+    {
+        Ok(map) => return Ok(Some(SymbolSource::SymbolMap(map))),
+        Err(e) => attempts.push(Error::from(e)),
     }
 
     // Step 3. If step two fails, then go to the internet to look for the symbols.
-    // Build a query.
-    let symbol_file = query_symbol_file_server(&lib.name, &lib.breakpadId);
-    None
+    let symbols = match symbol_server
+        .fetch_breakpad_symbols(&lib.debugName, &lib.breakpadId)
+        .await
+    {
+        Ok(symbol_file) => BreakpadSymbols::from_path(&symbol_file),
+        Err(e) => Err(e),
+    };
+    match symbols {
+        Ok(symbols) => Ok(Some(SymbolSource::Breakpad(symbols))),
+        Err(e) => {
+            attempts.push(e);
+            Err(Error::MissingDebugFile {
+                library: lib.debugName.clone(),
+                breakpad_id: lib.breakpadId.clone(),
+                attempts,
+            })
+        }
+    }
 }
 
 pub async fn get_lib_info(
@@ -93,30 +108,48 @@ pub async fn get_lib_info(
 
     join_all(libs.iter().map(|lib| {
         // Try and get a symbol map for the binary.
-        let symbol_map = sm.load_symbol_map_for_binary_at_path(Path::new(&lib.path), None);
-        // TODO: We should try and disambiguate!
-        let library_info =
-            SymbolManager::library_info_for_binary_at_path(Path::new(&lib.path), None);
+        let symbol_map = sm.load_symbol_map_for_binary_at_path(
+            Path::new(&lib.path),
+            libraries::disambiguator(lib),
+        );
+        let library_info = SymbolManager::library_info_for_binary_at_path(
+            Path::new(&lib.path),
+            libraries::disambiguator(lib),
+        );
         join(symbol_map, library_info)
     }))
     .await
 }
 
-
-pub async fn gather_samples(profile: fx_processed_profile::Profile) -> () {
+/// Print the symbol maps that can be found for a profile's libraries, and return the errors from looking for them, so that the caller can report them.
+pub async fn gather_samples(
+    profile: fx_processed_profile::Profile,
+    symbol_server: &SymbolServer,
+    allowlist: &LibraryAllowlist,
+) -> Vec<Error> {
     println!("Gathering samples.");
+    let mut errors = vec![];
     let libs = &profile.libs;
 
     let sm = SymbolManager::with_config(SymbolManagerConfig::new());
     // Start off by getting the symbols with samply.
     let clibs = &profile.libs.clone();
     for lib in clibs {
-        if let Some(sym_map) = find_symbol_map(lib,&sm ).await {
-            println!("Found symbol map for: {:?}", sym_map.symbol_file_origin());
-            println!("\tSymbol count: {:?}", sym_map.symbol_count());
-            if sym_map.symbol_count() < 100 {
-                for (id, name) in sym_map.iter_symbols() {
-                    println!("\t\tSymbol: {:?} -- {:?}", id, name);
+        let symbols = match find_symbol_map(lib, &sm, symbol_server, allowlist).await {
+            Ok(symbols) => symbols,
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        };
+        if let Some(symbols) = symbols {
+            println!("Found symbol map for: {}", symbols.origin());
+            println!("\tSymbol count: {:?}", symbols.symbol_count());
+            if let SymbolSource::SymbolMap(sym_map) = &symbols {
+                if sym_map.symbol_count() < 100 {
+                    for (id, name) in sym_map.iter_symbols() {
+                        println!("\t\tSymbol: {:?} -- {:?}", id, name);
+                    }
                 }
             }
         }
@@ -161,6 +194,86 @@ pub async fn gather_samples(profile: fx_processed_profile::Profile) -> () {
     //         println!("Failed to load libraryinfo for library");
     //     }
     // });
+    errors
+}
+
+/// Aggregate the samples from every thread of a profile into a single sample profile, ready to be written out by one of the `sample_profile` writers.
+/// Library binaries that are present on this machine are used to find the length of function prologues.
+/// The profile's tables are validated first, as transposition relies on them being consistent.
+pub fn aggregate_profile(profile: &fx_processed_profile::Profile) -> Result<SampleProfile, Error> {
+    aggregate_filtered_profile(profile, &SampleFilter::default())
+        .map(|(sample_profile, _)| sample_profile)
+}
+
+/// Aggregate the samples of a profile that pass a filter, and count the samples that were left out.
+/// Threads that aren't weighted by sample counts are an error, unless the filter's weight policy skips or converts them.
+pub fn aggregate_filtered_profile(
+    profile: &fx_processed_profile::Profile,
+    filter: &SampleFilter,
+) -> Result<(SampleProfile, DiscardedSamples), Error> {
+    fx_import::check_tables(profile)?;
+    if filter.weights == WeightPolicy::Reject {
+        let unsupported = profile.threads.iter().position(|thread| {
+            filter.threads.matches(thread) && thread.samples.weightType != WeightType::Samples
+        });
+        if let Some(thread) = unsupported {
+            return Err(Error::UnsupportedWeightType {
+                thread,
+                weight_type: profile.threads[thread].samples.weightType,
+            });
+        }
+    }
+    let (samples, discarded) = transposed::transpose_filtered_samples(profile, filter);
+    let mut classifier = PrologueClassifier::with_binaries(&profile.libs);
+    let mut sample_profile = SampleProfile::new();
+    sample_profile.add_transposed_samples(&samples, &mut classifier);
+    Ok((sample_profile, discarded))
 }
 
-fn symbolicate() {}
+/// Symbolicate a profile, using the symbols that we can find for each of its allowed libraries, either on this machine or on the symbol server.
+/// The result is a new profile, with resolved function names, file names and line numbers, which can be serialized back to JSON.
+/// Profiles that are already marked as symbolicated are returned unchanged. The result is only marked as symbolicated if we found symbols for every library with native frames, including those outside the allowlist, so that a partly symbolicated profile can be retried.
+/// A library that we can't find symbols for doesn't stop the rest of the profile from being symbolicated; instead, the reason is returned alongside the profile.
+pub async fn symbolicate(
+    profile: fx_processed_profile::Profile,
+    symbol_server: &SymbolServer,
+    allowlist: &LibraryAllowlist,
+) -> (fx_processed_profile::Profile, Vec<Error>) {
+    symbolicate_build(profile, symbol_server, allowlist, &BuildFilter::default()).await
+}
+
+/// Symbolicate only the libraries of a profile that belong to the builds in `build`, which saves looking for the symbols of builds whose samples won't be used.
+/// The profile's tables are validated first, as symbolication indexes them directly. A profile with inconsistent tables is returned unchanged, along with the reason.
+pub async fn symbolicate_build(
+    profile: fx_processed_profile::Profile,
+    symbol_server: &SymbolServer,
+    allowlist: &LibraryAllowlist,
+    build: &BuildFilter,
+) -> (fx_processed_profile::Profile, Vec<Error>) {
+    if let Err(e) = fx_import::check_tables(&profile) {
+        return (profile, vec![e]);
+    }
+    if profile.meta.symbolicated == Some(true) {
+        return (profile, vec![]);
+    }
+    let sm = SymbolManager::with_config(SymbolManagerConfig::new());
+    let results = join_all(profile.libs.iter().map(|lib| async {
+        if build.matches(lib) {
+            find_symbol_map(lib, &sm, symbol_server, allowlist).await
+        } else {
+            Ok(None)
+        }
+    }))
+    .await;
+    let mut errors = vec![];
+    let sources: Vec<Option<SymbolSource>> = results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|e| {
+                errors.push(e);
+                None
+            })
+        })
+        .collect();
+    (symbolication::symbolicate_profile(profile, &sources), errors)
+}
@@ -0,0 +1,174 @@
+// Helpers for deciding which of a profile's libraries to symbolicate, and how to find their symbols on each platform.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use debugid::{CodeId, DebugId};
+use wholesym::{LibraryInfo, MultiArchDisambiguator, SymbolManager};
+
+use crate::error::Error;
+use crate::fx_processed_profile::Lib;
+
+// The libraries that contain Mozilla code, on each platform that Firefox ships on.
+const MACOS_LIBS: [&str; 3] = ["XUL", "firefox", "libmozglue.dylib"];
+const LINUX_LIBS: [&str; 4] = ["libxul.so", "firefox", "firefox-bin", "libmozgtk.so"];
+const WINDOWS_LIBS: [&str; 3] = ["xul.dll", "firefox.exe", "mozglue.dll"];
+
+/// A list of library names that we want to symbolicate and optimise.
+/// We only care about libraries that contain Mozilla source code, as those are the only ones that we can rebuild with a PGO profile.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LibraryAllowlist {
+    names: Vec<String>,
+}
+
+impl LibraryAllowlist {
+    pub fn new<S: Into<String>, I: IntoIterator<Item = S>>(names: I) -> LibraryAllowlist {
+        LibraryAllowlist {
+            names: names.into_iter().map(|n| n.into()).collect(),
+        }
+    }
+
+    /// The Firefox libraries for macOS, Linux and Windows.
+    pub fn mozilla() -> LibraryAllowlist {
+        LibraryAllowlist::new(
+            MACOS_LIBS
+                .iter()
+                .chain(LINUX_LIBS.iter())
+                .chain(WINDOWS_LIBS.iter())
+                .copied(),
+        )
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Libraries are matched on either their name or their debug name, as on Windows the debug name refers to the `.pdb` file rather than the `.dll`.
+    pub fn contains(&self, lib: &Lib) -> bool {
+        self.names
+            .iter()
+            .any(|name| *name == lib.name || *name == lib.debugName)
+    }
+}
+
+impl Default for LibraryAllowlist {
+    fn default() -> Self {
+        LibraryAllowlist::mozilla()
+    }
+}
+
+/// The builds that we want to keep samples from, identified by the debug IDs or code IDs of their libraries.
+/// Addresses and line numbers only make sense for the exact build that they were sampled in, so profiles of other builds must not be mixed into the same PGO profile.
+/// An empty filter matches every library.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct BuildFilter {
+    debug_ids: Vec<DebugId>,
+    code_ids: Vec<CodeId>,
+    /// The debug names of the builds' libraries, where we know them.
+    debug_names: Vec<String>,
+}
+
+impl BuildFilter {
+    /// A filter for the builds with these debug IDs, given in any of the forms that `parse_debug_id` accepts.
+    pub fn from_debug_ids<S: AsRef<str>, I: IntoIterator<Item = S>>(
+        ids: I,
+    ) -> Result<BuildFilter, Error> {
+        let mut filter = BuildFilter::default();
+        for id in ids {
+            filter.add_debug_id(parse_debug_id(id.as_ref())?);
+        }
+        Ok(filter)
+    }
+
+    pub fn add_debug_id(&mut self, id: DebugId) {
+        self.debug_ids.push(id);
+    }
+
+    pub fn add_code_id(&mut self, id: CodeId) {
+        self.code_ids.push(id);
+    }
+
+    /// Add the build of a binary on this machine, using the debug ID and code ID that wholesym finds in it.
+    pub async fn add_binary(&mut self, path: &Path) -> Result<(), Error> {
+        let info = SymbolManager::library_info_for_binary_at_path(path, None).await?;
+        if info.debug_id.is_none() && info.code_id.is_none() {
+            return Err(Error::MissingBuildId {
+                path: path.to_path_buf(),
+            });
+        }
+        self.debug_ids.extend(info.debug_id);
+        self.code_ids.extend(info.code_id);
+        self.debug_names.extend(info.debug_name);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.debug_ids.is_empty() && self.code_ids.is_empty()
+    }
+
+    pub fn matches(&self, lib: &Lib) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let debug_id_matches = debug_id(lib).is_some_and(|id| self.debug_ids.contains(&id));
+        let code_id_matches = lib
+            .codeId
+            .as_ref()
+            .is_some_and(|id| self.code_ids.contains(&CodeId::new(id.clone())));
+        debug_id_matches || code_id_matches
+    }
+
+    /// Find the libraries of a profile that are other builds of the filter's libraries, i.e. that have the debug name of one of them, but not its ID.
+    /// The names of the filter's libraries are those of the binaries that it was given, and of the libraries of the profile that it matches.
+    /// Libraries of any other name (such as system libraries) aren't other builds, even though the filter doesn't match them.
+    pub fn other_builds(&self, libs: &[Lib]) -> Vec<bool> {
+        if self.is_empty() {
+            return vec![false; libs.len()];
+        }
+        let names: Vec<&str> = self
+            .debug_names
+            .iter()
+            .map(String::as_str)
+            .chain(
+                libs.iter()
+                    .filter(|lib| self.matches(lib))
+                    .map(|lib| lib.debugName.as_str()),
+            )
+            .collect();
+        libs.iter()
+            .map(|lib| !self.matches(lib) && names.contains(&lib.debugName.as_str()))
+            .collect()
+    }
+}
+
+/// Parse a debug ID either in breakpad's form, as used by `Lib.breakpadId` and symbol servers (e.g. `1629FCF0BE5C8860C0E1ADF03B0048FB0`), or as a UUID with an optional age (e.g. `1629fcf0-be5c-8860-c0e1-adf03b0048fb`).
+pub fn parse_debug_id(id: &str) -> Result<DebugId, Error> {
+    DebugId::from_breakpad(id)
+        .or_else(|_| DebugId::from_str(id))
+        .map_err(|_| Error::InvalidBuildId(id.to_string()))
+}
+
+pub fn debug_id(lib: &Lib) -> Option<DebugId> {
+    DebugId::from_breakpad(&lib.breakpadId).ok()
+}
+
+/// Pick the right binary out of a multi-arch (i.e. macOS universal) file.
+/// The debug ID identifies the exact binary that was profiled, so we prefer it over the architecture name.
+pub fn disambiguator(lib: &Lib) -> Option<MultiArchDisambiguator> {
+    debug_id(lib)
+        .map(MultiArchDisambiguator::DebugId)
+        .or_else(|| lib.arch.clone().map(MultiArchDisambiguator::Arch))
+}
+
+/// Describe a library to wholesym, which can then use the debug ID, code ID and paths to find its debug files in the platform-specific locations (such as dSYM bundles, build-id directories or next to a PDB path).
+pub fn library_info(lib: &Lib) -> LibraryInfo {
+    LibraryInfo {
+        debug_name: Some(lib.debugName.clone()),
+        debug_id: debug_id(lib),
+        debug_path: Some(lib.debugPath.clone()),
+        name: Some(lib.name.clone()),
+        code_id: lib.codeId.as_ref().and_then(|id| id.parse().ok()),
+        path: Some(lib.path.clone()),
+        arch: lib.arch.clone(),
+    }
+}
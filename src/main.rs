@@ -1,747 +1,434 @@
-use clap::Parser;
-use futures::FutureExt;
-use fx_processed_to_clang;
-use serde_json::{from_str, json};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::env;
-use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
-use tokio::main;
+use std::process;
 
+use fx_processed_to_clang::error::{Error, Result};
+use fx_processed_to_clang::fx_import;
 use fx_processed_to_clang::fx_processed_profile::Profile;
+use fx_processed_to_clang::libraries::{BuildFilter, LibraryAllowlist};
+use fx_processed_to_clang::merge::{self, MergeInput};
+use fx_processed_to_clang::sample_profile::{autofdo, extbinary, llvm_text, SampleProfile};
+use fx_processed_to_clang::symbol_server::{SymbolServer, MOZILLA_SYMBOL_SERVER};
+use fx_processed_to_clang::threads::{ThreadFilter, ThreadSelector};
+use fx_processed_to_clang::time_range::{TimeOrigin, TimeRange};
+use fx_processed_to_clang::transposed::{
+    CpuWeighting, DiscardedSamples, SampleFilter, WeightPolicy,
+};
+
+/// Convert processed Firefox profiles into clang PGO compatible profiles
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print information about the libraries and symbols found in a profile
+    Inspect {
+        #[arg(required = true)]
+        input_profile: PathBuf,
+        #[command(flatten)]
+        symbols: SymbolArgs,
+    },
+    /// Symbolicate a processed profile, and write it back out as JSON
+    Symbolicate {
+        #[arg(required = true)]
+        input_profile: PathBuf,
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        #[command(flatten)]
+        symbols: SymbolArgs,
+    },
+    /// Convert a processed profile into a PGO profile
+    Convert {
+        #[arg(required = true)]
+        input_profile: PathBuf,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[command(flatten)]
+        symbols: SymbolArgs,
+    },
+    /// Merge many processed profiles into a single PGO profile
+    Merge {
+        /// The profiles to merge, or directories of them
+        input_profiles: Vec<PathBuf>,
+        /// A profile (or directory of profiles) to merge, with a weight to multiply its samples by, as <weight>,<path>. May be given several times
+        #[arg(long = "weighted-input")]
+        weighted_inputs: Vec<String>,
+        /// A file listing the profiles to merge, one [<weight>,]<path> per line
+        #[arg(long)]
+        input_files: Option<PathBuf>,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[command(flatten)]
+        symbols: SymbolArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct OutputArgs {
+    #[arg(short, long, required = true)]
+    output: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = Format::LlvmText)]
+    format: Format,
+    /// Write MD5 hashes of function names instead of the names themselves (llvm-ext-binary only)
+    #[arg(long)]
+    md5: bool,
+}
 
-const JSON_STR: &str = {
-    r#"
-    {
-        "meta": {
-        "categories": [
-            {
-            "name": "Other",
-            "color": "grey",
-            "subcategories": [
-                "Other"
-            ]
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// The debug ID of a library of the build to keep samples from, either as a breakpad ID or a UUID. Samples from other builds are discarded. May be given several times, and defaults to every build
+    #[arg(long = "build-id")]
+    build_ids: Vec<String>,
+    /// A binary of the build to keep samples from, whose debug ID and code ID are read from the file. May be given several times
+    #[arg(long = "build-binary")]
+    build_binaries: Vec<PathBuf>,
+    /// Only keep samples of threads that match this selector, given as <property>=<value> terms separated by commas, e.g. name=GeckoMain,process-type=tab. The properties are name, process-type, process-name, pid and main-thread, and a term can be negated with a leading `!`. May be given several times, to keep the threads that match any of them
+    #[arg(long = "thread")]
+    threads: Vec<String>,
+    /// Discard samples of threads that match this selector, given in the same form as --thread. May be given several times
+    #[arg(long = "exclude-thread")]
+    excluded_threads: Vec<String>,
+    /// Only keep samples taken in this range, given as <start>..<end> in milliseconds from the start of the profile (as the profiler shows it). Either bound may be left out
+    #[arg(long)]
+    range: Option<String>,
+    /// Read --range as Unix timestamps in milliseconds, rather than from the start of the profile
+    #[arg(long, requires = "range")]
+    absolute_range: bool,
+    /// Only keep samples taken in the committed range of a Firefox Profiler URL, given as its `range` parameter, e.g. 1843m1500
+    #[arg(long)]
+    committed_range: Option<String>,
+    /// Only keep samples taken while their thread was registered with the profiler, leaving out thread startup and shutdown
+    #[arg(long)]
+    clip_to_thread_lifetime: bool,
+    /// What to do with threads whose samples are weighted by something other than sample counts, such as tracing durations or allocated bytes
+    #[arg(long, value_enum, default_value_t = Weights::Reject)]
+    weights: Weights,
+    /// How to count samples of threads that were waiting rather than running, according to their CPU usage
+    #[arg(long, value_enum, default_value_t = CpuWeights::WallClock)]
+    cpu_weighting: CpuWeights,
+    /// The fraction of the time between samples that a thread must have been on the CPU for its sample to be kept (with --cpu-weighting drop-idle)
+    #[arg(long, default_value_t = 0.05)]
+    idle_threshold: f64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Weights {
+    /// Fail, rather than count them as samples
+    Reject,
+    /// Leave them out of the profile
+    Skip,
+    /// Count tracing durations in sampling intervals, and anything else as a single sample
+    Convert,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CpuWeights {
+    /// Count every sample, whether or not its thread was running
+    WallClock,
+    /// Drop samples of threads that were idle for most of the time since their previous sample
+    DropIdle,
+    /// Scale samples by the share of the time since their previous sample that their thread was running, so that a sample of a thread that was running throughout still counts as one sample
+    Scale,
+}
+
+impl FilterArgs {
+    async fn sample_filter(&self) -> Result<SampleFilter> {
+        let mut build = BuildFilter::from_debug_ids(&self.build_ids)?;
+        for binary in &self.build_binaries {
+            build.add_binary(binary).await?;
+        }
+        let mut threads = ThreadFilter::default();
+        for selector in &self.threads {
+            threads.include(ThreadSelector::parse(selector)?);
+        }
+        for selector in &self.excluded_threads {
+            threads.exclude(ThreadSelector::parse(selector)?);
+        }
+        let mut ranges = vec![];
+        if let Some(range) = &self.range {
+            let origin = if self.absolute_range {
+                TimeOrigin::UnixEpoch
+            } else {
+                TimeOrigin::ProfileStart
+            };
+            ranges.push(TimeRange::parse(range, origin)?);
+        }
+        if let Some(range) = &self.committed_range {
+            ranges.push(TimeRange::from_committed_ranges(range)?);
+        }
+        let weights = match self.weights {
+            Weights::Reject => WeightPolicy::Reject,
+            Weights::Skip => WeightPolicy::Skip,
+            Weights::Convert => WeightPolicy::Convert,
+        };
+        let cpu = match self.cpu_weighting {
+            CpuWeights::WallClock => CpuWeighting::WallClock,
+            CpuWeights::DropIdle => CpuWeighting::DropIdle {
+                threshold: self.idle_threshold,
             },
-            {
-            "name": "Regular",
-            "color": "blue",
-            "subcategories": [
-                "Other"
-            ]
+            CpuWeights::Scale => CpuWeighting::Scale,
+        };
+        Ok(SampleFilter {
+            build,
+            threads,
+            ranges,
+            clip_to_thread_lifetime: self.clip_to_thread_lifetime,
+            weights,
+            cpu,
+        })
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct SymbolArgs {
+    /// The Breakpad/Tecken-style symbol server to fetch symbol files from
+    #[arg(long, default_value = MOZILLA_SYMBOL_SERVER)]
+    symbol_server: String,
+    /// The directory to cache downloaded symbol files in
+    #[arg(long)]
+    symbol_cache: Option<PathBuf>,
+    /// The name of a library to symbolicate. May be given several times, and defaults to the Firefox libraries for every platform
+    #[arg(long = "library")]
+    libraries: Vec<String>,
+}
+
+impl SymbolArgs {
+    fn symbol_server(&self) -> Result<SymbolServer> {
+        let cache_dir = self
+            .symbol_cache
+            .clone()
+            .unwrap_or_else(|| env::temp_dir().join("fx-processed-2-clang-symbols"));
+        SymbolServer::new(&self.symbol_server, cache_dir)
+    }
+
+    fn allowlist(&self) -> LibraryAllowlist {
+        if self.libraries.is_empty() {
+            LibraryAllowlist::mozilla()
+        } else {
+            LibraryAllowlist::new(self.libraries.iter().cloned())
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// LLVM's text sample profile format
+    LlvmText,
+    /// LLVM's extensible binary sample profile format, which is smaller and faster for clang to read
+    LlvmExtBinary,
+    /// GCC's AutoFDO profile format, as read by `gcc -fauto-profile`
+    GccAutofdo,
+}
+
+fn create_output(output: &PathBuf) -> Result<BufWriter<File>> {
+    let file = File::create(output).map_err(|e| Error::from(e).with_path(output))?;
+    Ok(BufWriter::new(file))
+}
+
+impl OutputArgs {
+    fn write_sample_profile(&self, mut sample_profile: SampleProfile) -> Result<()> {
+        let removed = sample_profile.remove_demangled_names();
+        if let Some(example) = removed.names.first() {
+            eprintln!(
+                "Warning: left out {} samples and {} names of functions whose symbols only have demangled names, which compilers can't match (such as {})",
+                removed.samples,
+                removed.names.len(),
+                example
+            );
+            eprintln!("Breakpad symbol files only have demangled names for C++ functions, so make the library's binary or debug file available to symbolicate it instead");
+        }
+        let sample_profile = &sample_profile;
+        let mut writer = create_output(&self.output)?;
+        let options = extbinary::Options { use_md5: self.md5 };
+        match self.format {
+            Format::LlvmText => llvm_text::write_profile(&mut writer, sample_profile),
+            Format::LlvmExtBinary => {
+                extbinary::write_profile(&mut writer, sample_profile, &options)
             }
-        ],
-        "debug": false,
-        "extensions": {
-            "baseURL": [],
-            "id": [],
-            "length": 0,
-            "name": []
-        },
-        "interval": 1.0,
-        "preprocessedProfileVersion": 46,
-        "processType": 0,
-        "product": "test",
-        "sampleUnits": {
-            "eventDelay": "ms",
-            "threadCPUDelta": "µs",
-            "time": "ms"
-        },
-        "startTime": 1636162232627.0,
-        "symbolicated": false,
-        "pausedRanges": [],
-        "version": 24,
-        "usesOnlyOneStackType": true,
-        "doesNotUseFrameImplementation": true,
-        "sourceCodeIsNotOnSearchfox": true,
-        "markerSchema": [
-            {
-            "name": "Text",
-            "display": [
-                "marker-chart",
-                "marker-table"
-            ],
-            "chartLabel": "{marker.data.name}",
-            "tableLabel": "{marker.name} - {marker.data.name}",
-            "data": [
-                {
-                "key": "name",
-                "label": "Details",
-                "format": "string",
-                "searchable": true
-                }
-            ]
-            },
-            {
-            "name": "custom",
-            "display": [
-                "marker-chart",
-                "marker-table"
-            ],
-            "tooltipLabel": "Custom tooltip label",
-            "data": [
-                {
-                "key": "eventName",
-                "label": "Event name",
-                "format": "string",
-                "searchable": true
-                },
-                {
-                "key": "allocationSize",
-                "label": "Allocation size",
-                "format": "bytes",
-                "searchable": true
-                },
-                {
-                "key": "url",
-                "label": "URL",
-                "format": "url",
-                "searchable": true
-                },
-                {
-                "key": "latency",
-                "label": "Latency",
-                "format": "duration",
-                "searchable": true
-                },
-                {
-                "label": "Description",
-                "value": "This is a test marker with a custom schema."
-                }
-            ]
+            Format::GccAutofdo => autofdo::write_profile(&mut writer, sample_profile),
+        }
+        .and_then(|_| writer.flush())
+        .map_err(|e| Error::from(e).with_path(&self.output))
+    }
+}
+
+/// Gather the inputs to merge from the positional arguments, the weighted inputs and the manifest, in that order.
+fn merge_inputs(
+    input_profiles: &[PathBuf],
+    weighted_inputs: &[String],
+    input_files: &Option<PathBuf>,
+) -> Result<Vec<MergeInput>> {
+    let mut inputs = vec![];
+    for path in input_profiles {
+        inputs.extend(merge::inputs_at(path, 1)?);
+    }
+    for weighted in weighted_inputs {
+        let input = MergeInput::parse_weighted(weighted)?;
+        inputs.extend(merge::inputs_at(&input.path, input.weight)?);
+    }
+    if let Some(manifest) = input_files {
+        inputs.extend(merge::read_manifest(manifest)?);
+    }
+    Ok(inputs)
+}
+
+fn report_discarded(discarded: &DiscardedSamples) {
+    if discarded.other_builds > 0 {
+        eprintln!(
+            "Discarded {} samples from other builds",
+            discarded.other_builds
+        );
+    }
+    if discarded.other_threads > 0 {
+        eprintln!(
+            "Discarded {} samples from other threads",
+            discarded.other_threads
+        );
+    }
+    if discarded.out_of_range > 0 {
+        eprintln!(
+            "Discarded {} samples from outside the time range",
+            discarded.out_of_range
+        );
+    }
+    if discarded.paused > 0 {
+        eprintln!(
+            "Discarded {} samples taken while the profiler was paused",
+            discarded.paused
+        );
+    }
+    if discarded.unsupported_weights > 0 {
+        eprintln!(
+            "Discarded {} samples that aren't weighted by sample counts",
+            discarded.unsupported_weights
+        );
+    }
+    if discarded.idle > 0 {
+        eprintln!("Discarded {} samples of idle threads", discarded.idle);
+    }
+}
+
+// Libraries without symbols are reported, but don't stop us from writing out the rest of the profile.
+async fn symbolicate(
+    input_profile: &PathBuf,
+    symbols: &SymbolArgs,
+    build: &BuildFilter,
+) -> Result<Profile> {
+    let parsed = fx_import::import_profile(input_profile)?;
+    let (symbolicated, errors) = fx_processed_to_clang::symbolicate_build(
+        parsed,
+        &symbols.symbol_server()?,
+        &symbols.allowlist(),
+        build,
+    )
+    .await;
+    for e in errors {
+        eprintln!("Warning: {}", e);
+    }
+    Ok(symbolicated)
+}
+
+async fn run(args: Args) -> Result<()> {
+    match args.command {
+        Command::Inspect {
+            input_profile,
+            symbols,
+        } => {
+            let parsed = fx_import::import_profile(&input_profile)?;
+            let errors = fx_processed_to_clang::gather_samples(
+                parsed,
+                &symbols.symbol_server()?,
+                &symbols.allowlist(),
+            )
+            .await;
+            for e in errors {
+                eprintln!("Warning: {}", e);
             }
-        ]
-        },
-        "libs": [
-        {
-            "name": "dump_syms",
-            "path": "/home/mstange/code/dump_syms/target/release/dump_syms",
-            "debugName": "dump_syms",
-            "debugPath": "/home/mstange/code/dump_syms/target/release/dump_syms",
-            "breakpadId": "5C0A0D51EA1980DF43F203B4525BE9BE0",
-            "codeId": "510d0a5c19eadf8043f203b4525be9be3dcb9554",
-            "arch": null
-        },
-        {
-            "name": "libc.so.6",
-            "path": "/usr/lib/x86_64-linux-gnu/libc.so.6",
-            "debugName": "libc.so.6",
-            "debugPath": "/usr/lib/x86_64-linux-gnu/libc.so.6",
-            "breakpadId": "1629FCF0BE5C8860C0E1ADF03B0048FB0",
-            "codeId": "f0fc29165cbe6088c0e1adf03b0048fbecbc003a",
-            "arch": null
         }
-        ],
-        "threads": [
-        {
-            "frameTable": {
-            "length": 16,
-            "address": [
-                -1,
-                796420,
-                911223,
-                1332248,
-                2354017,
-                2452862,
-                1700071,
-                172156,
-                1075602,
-                905942,
-                979918,
-                2437518,
-                1405368,
-                737506,
-                2586868,
-                674246
-            ],
-            "inlineDepth": [
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0
-            ],
-            "category": [
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1
-            ],
-            "subcategory": [
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0
-            ],
-            "func": [
-                0,
-                1,
-                2,
-                3,
-                4,
-                5,
-                6,
-                7,
-                8,
-                9,
-                10,
-                11,
-                12,
-                13,
-                14,
-                15
-            ],
-            "nativeSymbol": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                0,
-                1,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                2
-            ],
-            "innerWindowID": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "implementation": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "line": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "column": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "optimizations": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ]
-            },
-            "funcTable": {
-            "length": 16,
-            "name": [
-                0,
-                2,
-                3,
-                4,
-                5,
-                6,
-                8,
-                9,
-                10,
-                11,
-                12,
-                13,
-                14,
-                15,
-                16,
-                17
-            ],
-            "isJS": [
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false
-            ],
-            "relevantForJS": [
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false
-            ],
-            "resource": [
-                -1,
-                0,
-                0,
-                0,
-                0,
-                0,
-                1,
-                1,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                1
-            ],
-            "fileName": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "lineNumber": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ],
-            "columnNumber": [
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null,
-                null
-            ]
-            },
-            "markers": {
-                "length": 2,
-                "category": [
-                    0,
-                    0
-                ],
-                "data": [
-                    {
-                    "name": "Hello world!",
-                    "type": "Text"
-                    },
-                    {
-                    "allocationSize": 512000,
-                    "eventName": "My event",
-                    "latency": 123.0,
-                    "type": "custom",
-                    "url": "https://mozilla.org/"
-                    }
-                ],
-            "endTime": [
-                0.0,
-                2.0
-            ],
-            "name": [
-                18,
-                19
-            ],
-            "phase": [
-                0,
-                1
-            ],
-            "startTime": [
-                0.0,
-                0.0
-            ]
-            },
-            "name": "test",
-            "isMainThread": true,
-            "nativeSymbols": {
-            "length": 3,
-            "address": [
-                1700001,
-                172156,
-                674226
-            ],
-            "functionSize": [
-                180,
-                20,
-                44
-            ],
-            "libIndex": [
-                1,
-                1,
-                1
-            ],
-            "name": [
-                8,
-                9,
-                17
-            ]
-            },
-            "pausedRanges": [],
-            "pid": "123",
-            "processName": "test",
-            "processShutdownTime": null,
-            "processStartupTime": 0.0,
-            "processType": "default",
-            "registerTime": 0.0,
-            "resourceTable": {
-            "length": 2,
-            "lib": [
-                0,
-                1
-            ],
-            "name": [
-                1,
-                7
-            ],
-            "host": [
-                null,
-                null
-            ],
-            "type": [
-                1,
-                1
-            ]
-            },
-            "samples": {
-            "length": 4,
-            "stack": [
-                null,
-                6,
-                11,
-                15
-            ],
-            "time": [
-                0.0,
-                1.0,
-                2.0,
-                3.0
-            ],
-            "weight": [
-                1,
-                1,
-                1,
-                1
-            ],
-            "weightType": "samples",
-            "threadCPUDelta": [
-                0,
-                0,
-                0,
-                0
-            ]
-            },
-            "stackTable": {
-            "length": 16,
-            "prefix": [
-                null,
-                0,
-                1,
-                2,
-                3,
-                4,
-                5,
-                1,
-                7,
-                8,
-                9,
-                10,
-                7,
-                12,
-                13,
-                14
-            ],
-            "frame": [
-                0,
-                1,
-                2,
-                3,
-                4,
-                5,
-                6,
-                7,
-                8,
-                9,
-                10,
-                11,
-                12,
-                13,
-                14,
-                15
-            ],
-            "category": [
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1,
-                1
-            ],
-            "subcategory": [
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0
-            ]
-            },
-            "stringArray": [
-            "0x7ffdb4824837",
-            "dump_syms",
-            "0xc2704",
-            "0xde777",
-            "0x145418",
-            "0x23eb61",
-            "0x256d7e",
-            "libc.so.6",
-            "libc_symbol_1",
-            "libc_symbol_2",
-            "0x106992",
-            "0xdd2d6",
-            "0xef3ce",
-            "0x25318e",
-            "0x1571b8",
-            "0xb40e2",
-            "0x2778f4",
-            "libc_symbol_3",
-            "Experimental",
-            "CustomName"
-            ],
-            "tid": "12345",
-            "unregisterTime": null
+        Command::Symbolicate {
+            input_profile,
+            output,
+            symbols,
+        } => {
+            let symbolicated =
+                symbolicate(&input_profile, &symbols, &BuildFilter::default()).await?;
+            let mut writer = create_output(&output)?;
+            serde_json::to_writer(&mut writer, &symbolicated)
+                .map_err(|e| Error::from(e).with_path(&output))?;
+            writer
+                .flush()
+                .map_err(|e| Error::from(e).with_path(&output))?;
         }
-        ],
-        "pages": [],
-        "profilerOverhead": [],
-        "counters": [
-        {
-            "category": "Memory",
-            "name": "malloc",
-            "description": "Amount of allocated memory",
-            "mainThreadIndex": 0,
-            "pid": "123",
-            "sampleGroups": [
-            {
-                "id": 0,
-                "samples": {
-                "length": 3,
-                "count": [
-                    0.0,
-                    1000.0,
-                    800.0
-                ],
-                "number": [
-                    0,
-                    2,
-                    1
-                ],
-                "time": [
-                    0.0,
-                    1.0,
-                    2.0
-                ]
-                }
+        Command::Convert {
+            input_profile,
+            output,
+            filter,
+            symbols,
+        } => {
+            let filter = filter.sample_filter().await?;
+            let symbolicated = symbolicate(&input_profile, &symbols, &filter.build).await?;
+            let (sample_profile, discarded) =
+                fx_processed_to_clang::aggregate_filtered_profile(&symbolicated, &filter)?;
+            report_discarded(&discarded);
+            output.write_sample_profile(sample_profile)?;
+        }
+        Command::Merge {
+            input_profiles,
+            weighted_inputs,
+            input_files,
+            output,
+            filter,
+            symbols,
+        } => {
+            let inputs = merge_inputs(&input_profiles, &weighted_inputs, &input_files)?;
+            let filter = filter.sample_filter().await?;
+            let (sample_profile, report) = merge::merge_profiles(
+                &inputs,
+                &symbols.symbol_server()?,
+                &symbols.allowlist(),
+                &filter,
+            )
+            .await?;
+            for (path, e) in &report.warnings {
+                eprintln!("Warning: {}: {}", path.display(), e);
+            }
+            for (path, e) in &report.skipped {
+                eprintln!("Skipping {}: {}", path.display(), e);
             }
-            ]
+            for path in &report.empty {
+                eprintln!(
+                    "Skipping {}: no samples were left after filtering",
+                    path.display()
+                );
+            }
+            if report.merged == 0 {
+                return Err(Error::NoProfiles);
+            }
+            eprintln!("Merged {} of {} profiles", report.merged, inputs.len());
+            report_discarded(&report.discarded);
+            output.write_sample_profile(sample_profile)?;
         }
-        ]
     }
-    "#
-};
-
-/// Simple program to greet a person
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(required = true)]
-    input_profile: PathBuf,
-    // #[arg(required = true)]c
-    // output_profile: PathBuf,
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let raw_json = std::fs::read_to_string(&args.input_profile).expect(
-        format!(
-            "Could not read input file {}",
-            &args.input_profile.display()
-        )
-        .as_str(),
-    );
-
-    let parsed: Profile = serde_json::from_str(raw_json.as_str()).expect("Error parsing json");
-    fx_processed_to_clang::gather_samples(parsed).await;
-    // let serialized =
-    //     serde_json::to_string_pretty(&parsed).expect("Could not serialise back to JSON");
-    // println!("{}", serialized);
-    // println!("With text:\n{:#?}", parsed);
+    if let Err(e) = run(args).await {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,181 @@
+// Merging many processed profiles into a single sample profile.
+// Each profile is symbolicated and aggregated on its own, and the results are summed, optionally weighting some profiles more heavily than others.
+// Inputs can be given in the same forms as `llvm-profdata merge` accepts them: as profiles, weighted profiles, or a manifest that lists them.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::fx_import;
+use crate::libraries::LibraryAllowlist;
+use crate::sample_profile::SampleProfile;
+use crate::symbol_server::SymbolServer;
+use crate::transposed::{DiscardedSamples, SampleFilter};
+
+/// A profile to merge, and the weight to multiply its samples by.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MergeInput {
+    pub path: PathBuf,
+    pub weight: u64,
+}
+
+impl MergeInput {
+    pub fn new<P: Into<PathBuf>>(path: P) -> MergeInput {
+        MergeInput {
+            path: path.into(),
+            weight: 1,
+        }
+    }
+
+    /// Parse a weighted input of the form `<weight>,<path>`, as given to `llvm-profdata merge --weighted-input`. Weights must be positive integers.
+    pub fn parse_weighted(input: &str) -> Result<MergeInput> {
+        let invalid = || Error::InvalidMergeInput {
+            path: None,
+            line: None,
+            input: input.to_string(),
+        };
+        let (weight, path) = input.split_once(',').ok_or_else(invalid)?;
+        let weight = weight
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&weight| weight > 0)
+            .ok_or_else(invalid)?;
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(invalid());
+        }
+        Ok(MergeInput {
+            path: path.into(),
+            weight,
+        })
+    }
+}
+
+fn is_profile_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    path.is_file() && (name.ends_with(".json") || name.ends_with(".json.gz"))
+}
+
+/// Expand a path into the profiles to merge, with the same weight: a directory stands for the `.json` and `.json.gz` files directly inside it, in order of their names, and anything else for itself.
+pub fn inputs_at(path: &Path, weight: u64) -> Result<Vec<MergeInput>> {
+    if !path.is_dir() {
+        return Ok(vec![MergeInput {
+            path: path.to_path_buf(),
+            weight,
+        }]);
+    }
+    let mut paths = vec![];
+    for entry in fs::read_dir(path).map_err(|e| Error::from(e).with_path(path))? {
+        let entry = entry.map_err(|e| Error::from(e).with_path(path))?;
+        if is_profile_file(&entry.path()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths
+        .into_iter()
+        .map(|path| MergeInput { path, weight })
+        .collect())
+}
+
+/// Read a manifest of the profiles to merge, in the form read by `llvm-profdata merge --input-files`: one `[<weight>,]<path>` per line.
+/// Blank lines and lines starting with `#` are skipped, and relative paths are relative to the manifest. Directories are expanded with `inputs_at`.
+pub fn read_manifest(path: &Path) -> Result<Vec<MergeInput>> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::from(e).with_path(path))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut inputs = vec![];
+    for (ix, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let input = if line.contains(',') {
+            MergeInput::parse_weighted(line).map_err(|_| Error::InvalidMergeInput {
+                path: Some(path.to_path_buf()),
+                line: Some(ix + 1),
+                input: line.to_string(),
+            })?
+        } else {
+            MergeInput::new(line)
+        };
+        inputs.extend(inputs_at(&base.join(input.path), input.weight)?);
+    }
+    Ok(inputs)
+}
+
+/// What happened to the inputs of a merge.
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// The number of profiles that were merged, and had samples left to add after filtering.
+    pub merged: usize,
+    /// The profiles that were read, but none of whose samples were left after filtering, so that they added nothing to the merge.
+    pub empty: Vec<PathBuf>,
+    /// The profiles that couldn't be loaded or aggregated, and so were left out.
+    pub skipped: Vec<(PathBuf, Error)>,
+    /// Problems with profiles that were still merged, such as libraries that we couldn't find symbols for.
+    pub warnings: Vec<(PathBuf, Error)>,
+    /// The samples that the filter left out of the merged profiles, before they were weighted.
+    pub discarded: DiscardedSamples,
+}
+
+/// Symbolicate and aggregate each profile, and sum them into a single sample profile, with the samples of each multiplied by its weight.
+/// Libraries that don't match the filter's build aren't symbolicated, and their samples aren't counted.
+/// A profile that can't be used is skipped rather than failing the whole merge, and the report says why.
+/// Without a build in the filter, every profile must be of the same build of each library in the allowlist, as samples of different builds can't be mixed, and the merge fails if they aren't.
+pub async fn merge_profiles(
+    inputs: &[MergeInput],
+    symbol_server: &SymbolServer,
+    allowlist: &LibraryAllowlist,
+    filter: &SampleFilter,
+) -> Result<(SampleProfile, MergeReport)> {
+    let mut merged = SampleProfile::new();
+    let mut report = MergeReport::default();
+    // The breakpad IDs of the allowlisted libraries in the profiles so far, by debug name.
+    let mut builds: BTreeMap<String, String> = BTreeMap::new();
+    for input in inputs {
+        let profile = match fx_import::import_profile(&input.path) {
+            Ok(profile) => profile,
+            Err(e) => {
+                report.skipped.push((input.path.clone(), e));
+                continue;
+            }
+        };
+        if filter.build.is_empty() {
+            for lib in profile.libs.iter().filter(|lib| allowlist.contains(lib)) {
+                let build = builds
+                    .entry(lib.debugName.clone())
+                    .or_insert_with(|| lib.breakpadId.clone());
+                if *build != lib.breakpadId {
+                    return Err(Error::MixedBuilds {
+                        path: input.path.clone(),
+                        library: lib.debugName.clone(),
+                        breakpad_ids: (build.clone(), lib.breakpadId.clone()),
+                    });
+                }
+            }
+        }
+        let (symbolicated, errors) =
+            crate::symbolicate_build(profile, symbol_server, allowlist, &filter.build).await;
+        report
+            .warnings
+            .extend(errors.into_iter().map(|e| (input.path.clone(), e)));
+        match crate::aggregate_filtered_profile(&symbolicated, filter) {
+            Ok((sample_profile, discarded)) => {
+                report.discarded.add(&discarded);
+                if sample_profile.functions.is_empty() {
+                    report.empty.push(input.path.clone());
+                    continue;
+                }
+                merged.merge(&sample_profile, input.weight);
+                report.merged += 1;
+            }
+            Err(e) => report.skipped.push((input.path.clone(), e)),
+        }
+    }
+    Ok((merged, report))
+}
@@ -8,12 +8,38 @@ pub fn lookup_through_option<T>(op: &Option<T>) -> Option<&T> {
         None => None,
     }
 }
+
+/// A row that could not be looked up, because at least one of the table's columns doesn't reach that far.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MissingRow {
+    pub row: usize,
+    pub length: usize,
+}
+
 pub trait TableLookup<Entry> {
     fn length(&self) -> usize;
-    fn lookup(&self, ix: usize) -> Entry;
+    /// Look up a row, or return `None` if any of its columns are missing that row.
+    fn try_lookup(&self, ix: usize) -> Option<Entry>;
+    /// Look up a row, panicking if it doesn't exist. Only use this on tables that have been validated.
+    fn lookup(&self, ix: usize) -> Entry {
+        match self.try_lookup(ix) {
+            Some(entry) => entry,
+            None => panic!(
+                "Row {} is missing from a table of length {}",
+                ix,
+                self.length()
+            ),
+        }
+    }
     fn iter(&self) -> TableIterator<Self, Entry>
     where
         Self: Sized;
+    fn try_iter(&self) -> TryTableIterator<'_, Self, Entry>
+    where
+        Self: Sized,
+    {
+        TryTableIterator::from(self)
+    }
 }
 
 pub struct TableIterator<'a, T, E>
@@ -54,3 +80,53 @@ where
         }
     }
 }
+
+/// An iterator over the rows of a table, which reports the first row that can't be looked up, and then stops.
+pub struct TryTableIterator<'a, T, E>
+where
+    T: TableLookup<E>,
+{
+    cur_ix: usize,
+    failed: bool,
+    table: &'a T,
+    phantom: PhantomData<E>,
+}
+
+impl<'a, T, E> TryTableIterator<'a, T, E>
+where
+    T: TableLookup<E>,
+{
+    pub fn from(table: &T) -> TryTableIterator<'_, T, E> {
+        TryTableIterator {
+            cur_ix: 0,
+            failed: false,
+            table,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T, E> Iterator for TryTableIterator<'a, T, E>
+where
+    T: TableLookup<E>,
+{
+    type Item = Result<E, MissingRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.cur_ix >= self.table.length() {
+            return None;
+        }
+        let row = self.cur_ix;
+        self.cur_ix += 1;
+        match self.table.try_lookup(row) {
+            Some(entry) => Some(Ok(entry)),
+            None => {
+                self.failed = true;
+                Some(Err(MissingRow {
+                    row,
+                    length: self.table.length(),
+                }))
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+// Classification of sampled leaf frames into function "prologue" and "body" samples.
+// Clang's sample profile loader uses the ratio between the samples at the head of a function and the samples in its body to decide how often a function is called, relative to how much work it does per call. As we only have sampled program counters, we approximate the head samples by the samples which land in the prologue of a function.
+
+use std::fs::File;
+
+use debugid::DebugId;
+use fxhash::FxHashMap;
+use memmap2::Mmap;
+use object::{Object, ObjectSection, SectionKind};
+
+use crate::fx_processed_profile::table_address::{Address, TableAddress};
+use crate::fx_processed_profile::{IndexIntoLibs, Lib};
+use crate::libraries;
+use crate::transposed::TransposedSample;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FramePosition {
+    Prologue,
+    Body,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CodeArch {
+    X86_64,
+    Aarch64,
+}
+
+impl CodeArch {
+    /// Parse the architecture names used in `Lib.arch`.
+    pub fn from_lib_arch(arch: &str) -> Option<CodeArch> {
+        match arch {
+            "x86_64" | "x86_64h" | "amd64" => Some(CodeArch::X86_64),
+            "arm64" | "arm64e" | "aarch64" => Some(CodeArch::Aarch64),
+            _ => None,
+        }
+    }
+
+    /// The prologue length that we assume when we cannot inspect the code of a function.
+    /// This is the length of a frame pointer setup, i.e. `push rbp; mov rbp, rsp` on x86-64, or `stp x29, x30, [sp, #-16]!; mov x29, sp` on aarch64.
+    pub fn default_prologue_length(&self) -> u64 {
+        match self {
+            CodeArch::X86_64 => 4,
+            CodeArch::Aarch64 => 8,
+        }
+    }
+}
+
+// Most processed profiles don't record an architecture for their libraries, and the majority of them come from x86-64 machines.
+const FALLBACK_ARCH: CodeArch = CodeArch::X86_64;
+
+// The number of bytes at the start of a function that we inspect when looking for its prologue.
+const MAX_PROLOGUE_SCAN: usize = 64;
+
+fn x86_64_prologue_instruction_length(code: &[u8]) -> Option<usize> {
+    match code {
+        // endbr64
+        [0xf3, 0x0f, 0x1e, 0xfa, ..] => Some(4),
+        // push rbp, push rbx
+        [0x55, ..] | [0x53, ..] => Some(1),
+        // push r12 - push r15
+        [0x41, 0x54..=0x57, ..] => Some(2),
+        // mov rbp, rsp
+        [0x48, 0x89, 0xe5, ..] => Some(3),
+        // sub rsp, imm8
+        [0x48, 0x83, 0xec, _, ..] => Some(4),
+        // sub rsp, imm32
+        [0x48, 0x81, 0xec, _, _, _, _, ..] => Some(7),
+        _ => None,
+    }
+}
+
+fn aarch64_prologue_instruction_length(code: &[u8]) -> Option<usize> {
+    let instruction = u32::from_le_bytes(code.get(0..4)?.try_into().ok()?);
+    let is_prologue =
+        // paciasp, bti c
+        instruction == 0xd503233f || instruction == 0xd503245f
+        // stp xN, xM, [sp, #imm] and stp xN, xM, [sp, #imm]!
+        || instruction & 0xffc003e0 == 0xa90003e0
+        || instruction & 0xffc003e0 == 0xa98003e0
+        // mov x29, sp
+        || instruction == 0x910003fd
+        // sub sp, sp, #imm
+        || instruction & 0xff0003ff == 0xd10003ff;
+    if is_prologue {
+        Some(4)
+    } else {
+        None
+    }
+}
+
+/// Find the length of the prologue at the start of a function, by skipping over the instructions that compilers commonly emit to set up a stack frame.
+pub fn detect_prologue_length(arch: CodeArch, code: &[u8]) -> u64 {
+    let code = &code[..code.len().min(MAX_PROLOGUE_SCAN)];
+    let mut length = 0;
+    while length < code.len() {
+        let instruction_length = match arch {
+            CodeArch::X86_64 => x86_64_prologue_instruction_length(&code[length..]),
+            CodeArch::Aarch64 => aarch64_prologue_instruction_length(&code[length..]),
+        };
+        match instruction_length {
+            Some(l) => length += l,
+            None => break,
+        }
+    }
+    length as u64
+}
+
+/// Classify a sampled address, given the address and size of the function that it falls into, and the length of that function's prologue.
+pub fn classify_offset(
+    frame_address: Address,
+    symbol_address: Address,
+    function_size: Option<u32>,
+    prologue_length: u64,
+) -> FramePosition {
+    if frame_address < symbol_address {
+        return FramePosition::Body;
+    }
+    let offset = frame_address - symbol_address;
+    match function_size {
+        Some(size) if offset >= size as u64 => FramePosition::Body,
+        _ if offset < prologue_length => FramePosition::Prologue,
+        _ => FramePosition::Body,
+    }
+}
+
+/// The code of a library, mapped from disk, which we use to find the prologues of its functions.
+/// The file is only parsed once, when it is loaded, to check its build and find where its executable sections are.
+struct LibraryCode {
+    data: Mmap,
+    relative_address_base: Address,
+    sections: Vec<CodeSection>,
+}
+
+/// Where the contents of an executable section are, both in memory and in the library's file.
+struct CodeSection {
+    address: Address,
+    size: u64,
+    file_offset: u64,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The debug ID of a binary, derived in the same way as Breakpad and the Firefox profiler do for each platform.
+fn binary_debug_id(file: &object::File) -> Option<DebugId> {
+    if let Ok(Some(uuid)) = file.mach_uuid() {
+        // Mach-O UUIDs are used as they are.
+        return DebugId::from_breakpad(&format!("{}0", hex(&uuid))).ok();
+    }
+    if let Ok(Some(pdb_info)) = file.pdb_info() {
+        return DebugId::from_guid_age(&pdb_info.guid(), pdb_info.age()).ok();
+    }
+    if let Ok(Some(build_id)) = file.build_id() {
+        // ELF debug IDs are the first 16 bytes of the build ID, read as a little-endian GUID.
+        let mut guid = [0; 16];
+        let length = build_id.len().min(16);
+        guid[..length].copy_from_slice(&build_id[..length]);
+        return DebugId::from_guid_age(&guid, 0).ok();
+    }
+    None
+}
+
+/// Whether a binary is the build that a library was profiled in, judging by its debug ID, or by its ELF build ID (which is the code ID of Linux libraries).
+/// Binaries without either can't be checked, and so are never used.
+fn is_profiled_build(file: &object::File, lib: &Lib) -> bool {
+    let code_id_matches = match (file.build_id(), &lib.codeId) {
+        (Ok(Some(build_id)), Some(code_id)) => hex(build_id).eq_ignore_ascii_case(code_id),
+        _ => false,
+    };
+    let debug_id = libraries::debug_id(lib);
+    code_id_matches || (debug_id.is_some() && binary_debug_id(file) == debug_id)
+}
+
+impl LibraryCode {
+    /// Map the binary at a library's path, if it is the build that the library was profiled in.
+    fn load(lib: &Lib) -> Option<LibraryCode> {
+        let file = File::open(&lib.path).ok()?;
+        // The mapping is only read, and binaries aren't expected to change while we convert a profile.
+        let data = unsafe { Mmap::map(&file) }.ok()?;
+        let object = object::File::parse(&*data).ok()?;
+        if !is_profiled_build(&object, lib) {
+            return None;
+        }
+        let relative_address_base = object.relative_address_base();
+        let sections = object
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .filter_map(|section| {
+                let (file_offset, file_size) = section.file_range()?;
+                Some(CodeSection {
+                    address: section.address(),
+                    size: section.size().min(file_size),
+                    file_offset,
+                })
+            })
+            .collect();
+        Some(LibraryCode {
+            data,
+            relative_address_base,
+            sections,
+        })
+    }
+
+    fn function_code(&self, relative_address: Address, size: usize) -> Option<Vec<u8>> {
+        let address = self.relative_address_base + relative_address;
+        let section = self.sections.iter().find(|section| {
+            address >= section.address && address - section.address < section.size
+        })?;
+        let offset = address - section.address;
+        if offset + size as u64 > section.size {
+            return None;
+        }
+        let start = (section.file_offset + offset) as usize;
+        self.data.get(start..start + size).map(|code| code.to_vec())
+    }
+}
+
+/// A PrologueClassifier decides whether the leaf frame of a sample is in the prologue or the body of its function.
+/// Where the binary of a library is available on disk, and is the build that was profiled, prologue lengths are measured from the code of each function. Otherwise, we fall back to a fixed prologue length for the library's architecture.
+pub struct PrologueClassifier {
+    arches: Vec<CodeArch>,
+    binaries: Vec<Option<Lib>>,
+    code: FxHashMap<IndexIntoLibs, Option<LibraryCode>>,
+    prologue_lengths: FxHashMap<(IndexIntoLibs, Address), u64>,
+}
+
+impl PrologueClassifier {
+    /// Create a classifier that only uses the offset of a frame from the start of its symbol.
+    pub fn without_binaries(libs: &[Lib]) -> PrologueClassifier {
+        PrologueClassifier {
+            arches: libs
+                .iter()
+                .map(|lib| {
+                    lib.arch
+                        .as_deref()
+                        .and_then(CodeArch::from_lib_arch)
+                        .unwrap_or(FALLBACK_ARCH)
+                })
+                .collect(),
+            binaries: libs.iter().map(|_| None).collect(),
+            code: FxHashMap::default(),
+            prologue_lengths: FxHashMap::default(),
+        }
+    }
+
+    /// Create a classifier that will inspect the code of any library binary that is found at its `Lib.path`, as long as its debug ID or code ID match the library's.
+    /// Profiles are usually converted on another machine than the one they were taken on, so binaries of other builds, whose code is of no use to us, are common.
+    pub fn with_binaries(libs: &[Lib]) -> PrologueClassifier {
+        let mut classifier = PrologueClassifier::without_binaries(libs);
+        classifier.binaries = libs.iter().cloned().map(Some).collect();
+        classifier
+    }
+
+    fn library_code(&mut self, lib_index: IndexIntoLibs) -> Option<&LibraryCode> {
+        let binaries = &self.binaries;
+        self.code
+            .entry(lib_index)
+            .or_insert_with(|| {
+                binaries
+                    .get(lib_index as usize)
+                    .and_then(Option::as_ref)
+                    .and_then(LibraryCode::load)
+            })
+            .as_ref()
+    }
+
+    /// Whether the code of a library's binary is used to find the prologues of its functions.
+    pub fn uses_binary(&mut self, lib_index: IndexIntoLibs) -> bool {
+        self.library_code(lib_index).is_some()
+    }
+
+    fn prologue_length(
+        &mut self,
+        lib_index: IndexIntoLibs,
+        symbol_address: Address,
+        function_size: Option<u32>,
+    ) -> u64 {
+        if let Some(&length) = self.prologue_lengths.get(&(lib_index, symbol_address)) {
+            return length;
+        }
+        let arch = self
+            .arches
+            .get(lib_index as usize)
+            .copied()
+            .unwrap_or(FALLBACK_ARCH);
+        let scan_length = function_size
+            .map(|size| (size as usize).min(MAX_PROLOGUE_SCAN))
+            .unwrap_or(MAX_PROLOGUE_SCAN);
+        let length = self
+            .library_code(lib_index)
+            .and_then(|library| library.function_code(symbol_address, scan_length))
+            .map(|code| detect_prologue_length(arch, &code))
+            .unwrap_or_else(|| arch.default_prologue_length());
+        self.prologue_lengths
+            .insert((lib_index, symbol_address), length);
+        length
+    }
+
+    pub fn classify(&mut self, sample: &TransposedSample) -> FramePosition {
+        let frame_address = match sample.frame_address {
+            TableAddress::Address(a) => a,
+            _ => return FramePosition::Body,
+        };
+        let symbol = &sample.symbol_table_entry;
+        let prologue_length =
+            self.prologue_length(symbol.libIndex, symbol.address, symbol.functionSize);
+        classify_offset(
+            frame_address,
+            symbol.address,
+            symbol.functionSize,
+            prologue_length,
+        )
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use super::{FunctionSamples, LineLocation, SampleProfile};
+
+// A writer for GCC's AutoFDO profile format, as read by `gcc -fauto-profile`, and written by `create_gcov` from [AutoFDO](https://github.com/google/autofdo).
+// The format is defined by the reader in GCC's [auto-profile.cc](https://github.com/gcc-mirror/gcc/blob/master/gcc/auto-profile.cc), and uses the primitives of gcov data files:
+// 32-bit unsigned integers, 64-bit counters (written as their low and then high 32 bits), and strings (written as their length in bytes, including a terminating null, and then their bytes).
+// The file is laid out as:
+//
+//     magic, version, 0
+//     the string table:  tag, length, string count, strings
+//     the function instances:  tag, length, function count, then for each function: head samples, instance
+//     the module info:  tag, length, module count (always 0)
+//
+// where a function instance is:
+//
+//     name index, location count, callsite count
+//     for each location: offset, target count, samples, then for each target: histogram type, name index, samples
+//     for each callsite: offset, instance of the inlined callee
+//
+// Offsets combine a line offset (in the upper 16 bits) and a discriminator (in the lower 16 bits).
+// GCC computes the total samples of each instance itself, from the samples of its locations and its inlined callees, so they aren't written out.
+//
+// This matches `read_profile` in GCC 12 to 15, which is what we support. GCC 12 changed gcov strings to be measured in bytes, where older versions measure them (and pad them) in 4-byte words, so older versions can't read our string table.
+// Every version of GCC that we support expects `AUTO_PROFILE_VERSION` 1, and rejects files with any other version, so a GCC that changes the format will refuse our profiles rather than misreading them.
+// GCC skips over section lengths without checking them, and ignores the discriminators of body sample offsets.
+
+const GCOV_DATA_MAGIC: u32 = 0x67636461;
+/// GCC checks this against its own `AUTO_PROFILE_VERSION`, which is 1 in every version that we support.
+const AUTO_PROFILE_VERSION: u32 = 1;
+
+const GCOV_TAG_AFDO_FILE_NAMES: u32 = 0xaa000000;
+const GCOV_TAG_AFDO_FUNCTION: u32 = 0xac000000;
+const GCOV_TAG_AFDO_MODULE_GROUPING: u32 = 0xae000000;
+
+/// The histogram type of call targets. GCC only supports indirect call targets, and ignores the type when reading them.
+const HIST_TYPE_INDIR_CALL: u32 = 3;
+
+/// The combined line offset and discriminator that GCC uses to locate samples within a function.
+pub fn location_offset(location: &LineLocation) -> u32 {
+    (location.line_offset & 0xffff) << 16 | (location.discriminator & 0xffff)
+}
+
+struct GcovWriter<'a> {
+    buf: Vec<u8>,
+    names: BTreeMap<&'a str, u64>,
+}
+
+impl<'a> GcovWriter<'a> {
+    fn unsigned(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn counter(&mut self, value: u64) {
+        self.unsigned(value as u32);
+        self.unsigned((value >> 32) as u32);
+    }
+
+    /// Strings are written as GCC 12 and later read them, with their length in bytes and without padding.
+    fn string(&mut self, value: &str) {
+        self.unsigned(value.len() as u32 + 1);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Write a section with its tag, and fill in its length (in bytes) once its contents have been written.
+    fn section<F: FnOnce(&mut Self)>(&mut self, tag: u32, contents: F) {
+        self.unsigned(tag);
+        let length_at = self.buf.len();
+        self.unsigned(0);
+        contents(self);
+        let length = (self.buf.len() - length_at - 4) as u32;
+        self.buf[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn name_index(&self, name: &str) -> u64 {
+        self.names[name]
+    }
+
+    fn function_instance(&mut self, function: &FunctionSamples) {
+        self.unsigned(self.name_index(&function.name) as u32);
+        let locations = function.body_locations();
+        self.unsigned(locations.len() as u32);
+        let callsite_count: usize = function.callsite_samples.values().map(|c| c.len()).sum();
+        self.unsigned(callsite_count as u32);
+
+        for location in locations {
+            let targets = function.sorted_call_targets(&location);
+            self.unsigned(location_offset(&location));
+            self.unsigned(targets.len() as u32);
+            self.counter(function.body_samples.get(&location).copied().unwrap_or(0));
+            for (callee, count) in targets {
+                self.unsigned(HIST_TYPE_INDIR_CALL);
+                self.counter(self.name_index(callee));
+                self.counter(count);
+            }
+        }
+
+        for (location, callees) in &function.callsite_samples {
+            for callee in callees.values() {
+                self.unsigned(location_offset(location));
+                self.function_instance(callee);
+            }
+        }
+    }
+}
+
+/// Write a sample profile in GCC's AutoFDO format.
+pub fn write_profile<W: Write>(w: &mut W, profile: &SampleProfile) -> io::Result<()> {
+    let mut writer = GcovWriter {
+        buf: vec![],
+        names: profile.names().into_iter().zip(0..).collect(),
+    };
+    writer.unsigned(GCOV_DATA_MAGIC);
+    writer.unsigned(AUTO_PROFILE_VERSION);
+    writer.unsigned(0);
+
+    let names: Vec<&str> = writer.names.keys().copied().collect();
+    writer.section(GCOV_TAG_AFDO_FILE_NAMES, |writer| {
+        writer.unsigned(names.len() as u32);
+        for name in names {
+            writer.string(name);
+        }
+    });
+
+    let functions = profile.sorted_functions();
+    writer.section(GCOV_TAG_AFDO_FUNCTION, |writer| {
+        writer.unsigned(functions.len() as u32);
+        for function in functions {
+            writer.counter(function.head_samples);
+            writer.function_instance(function);
+        }
+    });
+
+    // GCC no longer uses module info, but still expects to find an empty section.
+    writer.section(GCOV_TAG_AFDO_MODULE_GROUPING, |writer| writer.unsigned(0));
+
+    w.write_all(&writer.buf)
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use super::{FunctionSamples, SampleProfile};
+
+// A writer for LLVM's extensible binary sample profile format (`SPF_Ext_Binary`), as read by `clang -fprofile-sample-use`, and written by `llvm-profdata merge --sample --extbinary`.
+// The format is defined by `SampleProfileWriterExtBinaryBase` in LLVM's [SampleProfWriter.cpp](https://github.com/llvm/llvm-project/blob/main/llvm/lib/ProfileData/SampleProfWriter.cpp), and is laid out as:
+//
+//     magic (ULEB128), version (ULEB128)
+//     section count (u64), then for each section: type, flags, offset, size (each u64)
+//     the contents of each section
+//
+// All fixed-width integers are little-endian, and section offsets are from the start of the file.
+// We write the same sections as LLVM's default layout does for a profile that isn't context-sensitive: the profile summary, the name table, the function offset table and the function profiles themselves. Function profiles refer to names by their index in the name table.
+
+const MAGIC: u64 = (b'S' as u64) << 56
+    | (b'P' as u64) << 48
+    | (b'R' as u64) << 40
+    | (b'O' as u64) << 32
+    | (b'F' as u64) << 24
+    | (b'4' as u64) << 16
+    | (b'2' as u64) << 8
+    | 0x4;
+const VERSION: u64 = 103;
+
+const SEC_PROFILE_SUMMARY: u64 = 1;
+const SEC_NAME_TABLE: u64 = 2;
+const SEC_FUNC_OFFSET_TABLE: u64 = 4;
+const SEC_LBR_PROFILE: u64 = 0x20;
+
+// Flags that are specific to a section type are stored in the upper 32 bits of the section's flags.
+const NAME_TABLE_MD5_NAME: u64 = 1 << 32;
+const NAME_TABLE_FIXED_LENGTH_MD5: u64 = 1 << 33;
+const NAME_TABLE_UNIQ_SUFFIX: u64 = 1 << 34;
+
+/// The suffix that clang's `-funique-internal-linkage-names` adds to the names of internal functions.
+const UNIQ_SUFFIX: &str = ".__uniq.";
+
+/// The cutoffs (in millionths of the total sample count) that LLVM computes the detailed profile summary for.
+pub const DEFAULT_CUTOFFS: [u32; 16] = [
+    10000, 100000, 200000, 300000, 400000, 500000, 600000, 700000, 800000, 900000, 950000, 990000,
+    999000, 999900, 999990, 999999,
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// Write the MD5 hashes of function names to the name table, rather than the names themselves.
+    /// This makes the profile smaller, but can only be used with clang, which hashes names in the same way when it reads the profile.
+    pub use_md5: bool,
+}
+
+/// The minimum sample count needed to cover `cutoff` millionths of the samples in a profile, and the number of counts at or above it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SummaryEntry {
+    pub cutoff: u32,
+    pub min_count: u64,
+    pub num_counts: u64,
+}
+
+/// The summary of a profile that clang uses to decide which code is hot or cold, as computed by LLVM's `SampleProfileSummaryBuilder`.
+/// Every body location (in every inlined callee) is a single count.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ProfileSummary {
+    pub total_count: u64,
+    pub max_count: u64,
+    pub max_function_count: u64,
+    pub num_counts: u64,
+    pub num_functions: u64,
+    pub detailed_summary: Vec<SummaryEntry>,
+}
+
+impl ProfileSummary {
+    pub fn from_profile(profile: &SampleProfile) -> ProfileSummary {
+        let mut summary = ProfileSummary::default();
+        let mut count_frequencies = BTreeMap::new();
+        for function in profile.functions.values() {
+            summary.num_functions += 1;
+            summary.max_function_count = summary.max_function_count.max(function.head_samples);
+            summary.add_counts(function, &mut count_frequencies);
+        }
+
+        // Walk the counts from the highest down, until we've seen enough of them to cover each cutoff.
+        let mut counts = count_frequencies.iter().rev();
+        let (mut sum, mut count, mut counts_seen) = (0u64, 0u64, 0u64);
+        for &cutoff in DEFAULT_CUTOFFS.iter() {
+            let desired = (summary.total_count as u128 * cutoff as u128 / 1_000_000) as u64;
+            while sum < desired {
+                match counts.next() {
+                    Some((&next_count, &frequency)) => {
+                        count = next_count;
+                        sum = sum.saturating_add(next_count.saturating_mul(frequency));
+                        counts_seen += frequency;
+                    }
+                    None => break,
+                }
+            }
+            summary.detailed_summary.push(SummaryEntry {
+                cutoff,
+                min_count: count,
+                num_counts: counts_seen,
+            });
+        }
+        summary
+    }
+
+    fn add_counts(&mut self, function: &FunctionSamples, frequencies: &mut BTreeMap<u64, u64>) {
+        for location in function.body_locations() {
+            let count = function.body_samples.get(&location).copied().unwrap_or(0);
+            self.total_count = self.total_count.saturating_add(count);
+            self.max_count = self.max_count.max(count);
+            self.num_counts += 1;
+            *frequencies.entry(count).or_insert(0) += 1;
+        }
+        for callees in function.callsite_samples.values() {
+            for callee in callees.values() {
+                self.add_counts(callee, frequencies);
+            }
+        }
+    }
+}
+
+/// The MD5 hash that LLVM uses in place of a function's name, which is the first 8 bytes of its MD5 digest, read as a little-endian integer.
+pub fn md5_name(name: &str) -> u64 {
+    let digest = md5::compute(name.as_bytes());
+    u64::from_le_bytes(digest.0[..8].try_into().unwrap())
+}
+
+fn write_uleb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+struct NameTable<'a> {
+    indices: BTreeMap<&'a str, u64>,
+}
+
+impl<'a> NameTable<'a> {
+    fn new(profile: &'a SampleProfile) -> NameTable<'a> {
+        NameTable {
+            indices: profile.names().into_iter().zip(0..).collect(),
+        }
+    }
+
+    fn write_index(&self, buf: &mut Vec<u8>, name: &str) {
+        write_uleb128(buf, self.indices[name]);
+    }
+
+    /// Write the names, in the order of their indices, and return the flags of the section.
+    fn write(&self, buf: &mut Vec<u8>, options: &Options) -> u64 {
+        write_uleb128(buf, self.indices.len() as u64);
+        if options.use_md5 {
+            for name in self.indices.keys() {
+                buf.extend_from_slice(&md5_name(name).to_le_bytes());
+            }
+            return NAME_TABLE_MD5_NAME | NAME_TABLE_FIXED_LENGTH_MD5;
+        }
+        for name in self.indices.keys() {
+            buf.extend_from_slice(name.as_bytes());
+            buf.push(0);
+        }
+        if self.indices.keys().any(|name| name.contains(UNIQ_SUFFIX)) {
+            NAME_TABLE_UNIQ_SUFFIX
+        } else {
+            0
+        }
+    }
+}
+
+fn write_summary(buf: &mut Vec<u8>, summary: &ProfileSummary) {
+    write_uleb128(buf, summary.total_count);
+    write_uleb128(buf, summary.max_count);
+    write_uleb128(buf, summary.max_function_count);
+    write_uleb128(buf, summary.num_counts);
+    write_uleb128(buf, summary.num_functions);
+    write_uleb128(buf, summary.detailed_summary.len() as u64);
+    for entry in &summary.detailed_summary {
+        write_uleb128(buf, entry.cutoff as u64);
+        write_uleb128(buf, entry.min_count);
+        write_uleb128(buf, entry.num_counts);
+    }
+}
+
+fn write_function_body(buf: &mut Vec<u8>, function: &FunctionSamples, names: &NameTable) {
+    names.write_index(buf, &function.name);
+    write_uleb128(buf, function.total_samples);
+
+    let locations = function.body_locations();
+    write_uleb128(buf, locations.len() as u64);
+    for location in locations {
+        write_uleb128(buf, location.line_offset as u64);
+        write_uleb128(buf, location.discriminator as u64);
+        write_uleb128(
+            buf,
+            function.body_samples.get(&location).copied().unwrap_or(0),
+        );
+        let targets = function.sorted_call_targets(&location);
+        write_uleb128(buf, targets.len() as u64);
+        for (callee, count) in targets {
+            names.write_index(buf, callee);
+            write_uleb128(buf, count);
+        }
+    }
+
+    let callsite_count: usize = function.callsite_samples.values().map(|c| c.len()).sum();
+    write_uleb128(buf, callsite_count as u64);
+    for (location, callees) in &function.callsite_samples {
+        for callee in callees.values() {
+            write_uleb128(buf, location.line_offset as u64);
+            write_uleb128(buf, location.discriminator as u64);
+            write_function_body(buf, callee, names);
+        }
+    }
+}
+
+struct Section {
+    ty: u64,
+    flags: u64,
+    data: Vec<u8>,
+}
+
+/// Write a sample profile in LLVM's extensible binary format.
+pub fn write_profile<W: Write>(
+    w: &mut W,
+    profile: &SampleProfile,
+    options: &Options,
+) -> io::Result<()> {
+    let names = NameTable::new(profile);
+
+    let mut summary = vec![];
+    write_summary(&mut summary, &ProfileSummary::from_profile(profile));
+
+    let mut name_table = vec![];
+    let name_table_flags = names.write(&mut name_table, options);
+
+    // The offset table lets clang skip straight to the functions that it is compiling. Offsets are from the start of the function profiles section.
+    let mut functions = vec![];
+    let mut offsets = vec![];
+    for function in profile.sorted_functions() {
+        offsets.push((function.name.as_str(), functions.len() as u64));
+        write_uleb128(&mut functions, function.head_samples);
+        write_function_body(&mut functions, function, &names);
+    }
+    let mut offset_table = vec![];
+    write_uleb128(&mut offset_table, offsets.len() as u64);
+    for (name, offset) in offsets {
+        names.write_index(&mut offset_table, name);
+        write_uleb128(&mut offset_table, offset);
+    }
+
+    // The offset table is listed before the function profiles, so that readers have it by the time they reach them.
+    let sections = [
+        Section {
+            ty: SEC_PROFILE_SUMMARY,
+            flags: 0,
+            data: summary,
+        },
+        Section {
+            ty: SEC_NAME_TABLE,
+            flags: name_table_flags,
+            data: name_table,
+        },
+        Section {
+            ty: SEC_FUNC_OFFSET_TABLE,
+            flags: 0,
+            data: offset_table,
+        },
+        Section {
+            ty: SEC_LBR_PROFILE,
+            flags: 0,
+            data: functions,
+        },
+    ];
+
+    let mut header = vec![];
+    write_uleb128(&mut header, MAGIC);
+    write_uleb128(&mut header, VERSION);
+    header.extend_from_slice(&(sections.len() as u64).to_le_bytes());
+    let mut offset = (header.len() + sections.len() * 4 * 8) as u64;
+    for section in &sections {
+        let size = section.data.len() as u64;
+        for field in [section.ty, section.flags, offset, size] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += size;
+    }
+
+    w.write_all(&header)?;
+    for section in &sections {
+        w.write_all(&section.data)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::io::{self, Write};
+
+use super::{FunctionSamples, LineLocation, SampleProfile};
+
+// A writer for LLVM's text sample profile format, as read by `clang -fprofile-sample-use`.
+// The format is described in the [clang documentation](https://clang.llvm.org/docs/UsersManual.html#sample-profile-text-format), and looks like:
+//
+//     function1:total_samples:total_head_samples
+//      offset1[.discriminator]: number_of_samples [fn1:num fn2:num ... ]
+//      offset2[.discriminator]: fn3:number_of_samples
+//       offset3[.discriminator]: number_of_samples
+//
+// where each additional level of indentation is an inlined callsite.
+
+fn write_location<W: Write>(w: &mut W, location: &LineLocation) -> io::Result<()> {
+    if location.discriminator == 0 {
+        write!(w, "{}", location.line_offset)
+    } else {
+        write!(w, "{}.{}", location.line_offset, location.discriminator)
+    }
+}
+
+fn write_function_body<W: Write>(
+    w: &mut W,
+    function: &FunctionSamples,
+    indent: usize,
+) -> io::Result<()> {
+    for location in function.body_locations() {
+        write!(w, "{:indent$}", "", indent = indent)?;
+        write_location(w, &location)?;
+        let count = function.body_samples.get(&location).copied().unwrap_or(0);
+        write!(w, ": {}", count)?;
+        for (callee, count) in function.sorted_call_targets(&location) {
+            write!(w, " {}:{}", callee, count)?;
+        }
+        writeln!(w)?;
+    }
+
+    for (location, callees) in &function.callsite_samples {
+        for callee in callees.values() {
+            write!(w, "{:indent$}", "", indent = indent)?;
+            write_location(w, location)?;
+            writeln!(w, ": {}:{}", callee.name, callee.total_samples)?;
+            write_function_body(w, callee, indent + 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a sample profile in LLVM's text format.
+pub fn write_profile<W: Write>(w: &mut W, profile: &SampleProfile) -> io::Result<()> {
+    for function in profile.sorted_functions() {
+        writeln!(
+            w,
+            "{}:{}:{}",
+            function.name, function.total_samples, function.head_samples
+        )?;
+        write_function_body(w, function, 1)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,370 @@
+pub mod autofdo;
+pub mod extbinary;
+pub mod llvm_text;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::prologue::{FramePosition, PrologueClassifier};
+use crate::transposed::{NativeFrame, TransposedSample};
+
+// Types for an aggregated, per-function sample profile.
+// These closely follow the `FunctionSamples` and `LineLocation` classes found in LLVM's [SampleProf.h](https://github.com/llvm/llvm-project/blob/main/llvm/include/llvm/ProfileData/SampleProf.h), so that writers for the various on-disk formats can share a single model.
+
+/// A location within a function, expressed as a line offset from the start of the function, and a discriminator to distinguish between multiple basic blocks on the same line.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct LineLocation {
+    pub line_offset: u32,
+    pub discriminator: u32,
+}
+
+impl LineLocation {
+    /// The location of `line` in a function that starts at `start_line`.
+    /// Like LLVM's `FunctionSamples::getOffset`, offsets are truncated to 16 bits, so that lines before the start of the function (e.g. from macros) wrap around rather than failing.
+    /// When either line is unknown, we fall back to the first line of the function.
+    /// Neither Breakpad symbols nor wholesym give us DWARF discriminators, so they are always zero.
+    pub fn from_lines(line: Option<u32>, start_line: Option<u32>) -> LineLocation {
+        match (line, start_line) {
+            (Some(line), Some(start_line)) => LineLocation {
+                line_offset: line.wrapping_sub(start_line) & 0xffff,
+                discriminator: 0,
+            },
+            _ => LineLocation::default(),
+        }
+    }
+}
+
+/// Whether a name is the one that the compiler gave a function in the object file, which is what clang and GCC match profiles by.
+/// Mangled C++ names (e.g. `_ZN7nsINode14GetTextContentER9nsAString`) and plain C names qualify, but demangled names (e.g. `nsINode::GetTextContent(nsAString&)`, as found in Mozilla's Breakpad symbols) contain characters that never appear in assembler names.
+pub fn is_assembler_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || "():<>,*&~".contains(c))
+}
+
+/// The samples collected for a single function, or for a single inlined instance of a function.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FunctionSamples {
+    pub name: String,
+    pub total_samples: u64,
+    pub head_samples: u64,
+    pub body_samples: BTreeMap<LineLocation, u64>,
+    // The functions called from each location in this function (that haven't been inlined), with the number of samples taken in each of them.
+    pub call_targets: BTreeMap<LineLocation, BTreeMap<String, u64>>,
+    // Inlined callees, keyed by the location of the callsite in this function, and then by callee name.
+    pub callsite_samples: BTreeMap<LineLocation, BTreeMap<String, FunctionSamples>>,
+}
+
+impl FunctionSamples {
+    pub fn new(name: &str) -> FunctionSamples {
+        FunctionSamples {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_body_samples(&mut self, location: LineLocation, count: u64) {
+        *self.body_samples.entry(location).or_insert(0) += count;
+        self.total_samples += count;
+    }
+
+    /// Find the samples for a callee that has been inlined at `location`, creating them if they don't exist yet.
+    pub fn inlined_callee_mut(
+        &mut self,
+        location: LineLocation,
+        name: &str,
+    ) -> &mut FunctionSamples {
+        self.callsite_samples
+            .entry(location)
+            .or_default()
+            .entry(name.to_string())
+            .or_insert_with(|| FunctionSamples::new(name))
+    }
+
+    /// Add samples to the body of an inlined callee, which is found by following a chain of inlined callsites from this function.
+    /// The samples are counted in the total of every function along the chain.
+    pub fn add_inlined_body_samples(
+        &mut self,
+        callsites: &[(LineLocation, &str)],
+        location: LineLocation,
+        count: u64,
+    ) {
+        match callsites.split_first() {
+            None => self.add_body_samples(location, count),
+            Some(((callsite, name), rest)) => {
+                self.total_samples += count;
+                self.inlined_callee_mut(*callsite, name)
+                    .add_inlined_body_samples(rest, location, count);
+            }
+        }
+    }
+
+    /// Record `count` samples in `callee`, called from `location`.
+    /// These samples were taken in the callee rather than this function, so they don't count towards the total samples of this function.
+    pub fn add_call_target_samples(&mut self, location: LineLocation, callee: &str, count: u64) {
+        *self
+            .call_targets
+            .entry(location)
+            .or_default()
+            .entry(callee.to_string())
+            .or_insert(0) += count;
+    }
+
+    /// Record samples in a call from an inlined callee, which is found by following a chain of inlined callsites from this function.
+    pub fn add_inlined_call_target_samples(
+        &mut self,
+        callsites: &[(LineLocation, &str)],
+        location: LineLocation,
+        callee: &str,
+        count: u64,
+    ) {
+        match callsites.split_first() {
+            None => self.add_call_target_samples(location, callee, count),
+            Some(((callsite, name), rest)) => self
+                .inlined_callee_mut(*callsite, name)
+                .add_inlined_call_target_samples(rest, location, callee, count),
+        }
+    }
+
+    /// The call targets at a location, ordered by decreasing sample count (and then by name), which is the order that LLVM writes them in.
+    pub fn sorted_call_targets(&self, location: &LineLocation) -> Vec<(&str, u64)> {
+        let mut targets: Vec<(&str, u64)> = self
+            .call_targets
+            .get(location)
+            .map(|targets| {
+                targets
+                    .iter()
+                    .map(|(name, count)| (name.as_str(), *count))
+                    .collect()
+            })
+            .unwrap_or_default();
+        targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        targets
+    }
+
+    /// Every location in the body of this function that has either samples or call targets.
+    pub fn body_locations(&self) -> Vec<LineLocation> {
+        let mut locations: Vec<LineLocation> = self
+            .body_samples
+            .keys()
+            .chain(self.call_targets.keys())
+            .copied()
+            .collect();
+        locations.sort();
+        locations.dedup();
+        locations
+    }
+
+    pub fn add_head_samples(&mut self, count: u64) {
+        self.head_samples += count;
+    }
+
+    /// The number of samples in the body of this function, excluding inlined callees.
+    pub fn body_sample_count(&self) -> u64 {
+        self.body_samples.values().sum()
+    }
+
+    /// Add the samples of another instance of this function, multiplied by `weight`.
+    /// Like LLVM's `FunctionSamples::merge`, counts saturate rather than overflowing.
+    pub fn merge(&mut self, other: &FunctionSamples, weight: u64) {
+        let weighted = |count: u64| count.saturating_mul(weight);
+        self.total_samples = self
+            .total_samples
+            .saturating_add(weighted(other.total_samples));
+        self.head_samples = self
+            .head_samples
+            .saturating_add(weighted(other.head_samples));
+        for (location, count) in &other.body_samples {
+            let body = self.body_samples.entry(*location).or_insert(0);
+            *body = body.saturating_add(weighted(*count));
+        }
+        for (location, targets) in &other.call_targets {
+            let merged = self.call_targets.entry(*location).or_default();
+            for (callee, count) in targets {
+                let target = merged.entry(callee.clone()).or_insert(0);
+                *target = target.saturating_add(weighted(*count));
+            }
+        }
+        for (location, callees) in &other.callsite_samples {
+            for callee in callees.values() {
+                self.inlined_callee_mut(*location, &callee.name)
+                    .merge(callee, weight);
+            }
+        }
+    }
+
+    /// Remove the call targets and inlined callees whose names aren't assembler names, and add their names to `removed`.
+    /// The samples of a removed callee are counted at its callsite instead, so that the totals of this function stay the same.
+    fn remove_demangled_names(&mut self, removed: &mut BTreeSet<String>) {
+        for targets in self.call_targets.values_mut() {
+            targets.retain(|name, _| {
+                let keep = is_assembler_name(name);
+                if !keep {
+                    removed.insert(name.clone());
+                }
+                keep
+            });
+        }
+        self.call_targets.retain(|_, targets| !targets.is_empty());
+        for (location, callees) in self.callsite_samples.iter_mut() {
+            for callee in callees.values_mut() {
+                callee.remove_demangled_names(removed);
+            }
+            let (kept, dropped) = std::mem::take(callees)
+                .into_iter()
+                .partition(|(name, _)| is_assembler_name(name));
+            *callees = kept;
+            for (name, callee) in dropped {
+                *self.body_samples.entry(*location).or_insert(0) += callee.total_samples;
+                removed.insert(name);
+            }
+        }
+        self.callsite_samples
+            .retain(|_, callees| !callees.is_empty());
+    }
+
+    /// Add every name that this function refers to: its own, its call targets', and those of its inlined callees.
+    fn collect_names<'a>(&'a self, names: &mut BTreeSet<&'a str>) {
+        names.insert(&self.name);
+        for targets in self.call_targets.values() {
+            names.extend(targets.keys().map(|name| name.as_str()));
+        }
+        for callees in self.callsite_samples.values() {
+            for callee in callees.values() {
+                callee.collect_names(names);
+            }
+        }
+    }
+}
+
+/// What `SampleProfile::remove_demangled_names` removed from a profile.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RemovedNames {
+    /// The names of the functions, call targets and inlined callees that were removed.
+    pub names: BTreeSet<String>,
+    /// The total samples of the functions that were removed. The samples of inlined callees are kept at their callsites, so they aren't counted.
+    pub samples: u64,
+}
+
+/// A SampleProfile is the aggregated form of one or more processed profiles, keyed by function name.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SampleProfile {
+    pub functions: BTreeMap<String, FunctionSamples>,
+}
+
+impl SampleProfile {
+    pub fn new() -> SampleProfile {
+        SampleProfile::default()
+    }
+
+    pub fn function_mut(&mut self, name: &str) -> &mut FunctionSamples {
+        self.functions
+            .entry(name.to_string())
+            .or_insert_with(|| FunctionSamples::new(name))
+    }
+
+    /// Aggregate a set of transposed samples into this profile, counting each one as many times as its weight.
+    /// Samples that land in the prologue of a function are counted as head samples, as well as being counted at the first line of the function's body.
+    /// Samples in functions that have been inlined are counted in the body of the inlined callee, nested under the callsites that they were inlined through.
+    /// Every caller on the stack of a sample records a call target sample for the function that it called, which is how LLVM finds the hot targets of each callsite.
+    /// Samples, callsites and calls are located by the line of their frame, relative to the line that the frame's function starts on.
+    pub fn add_transposed_samples(
+        &mut self,
+        samples: &[TransposedSample],
+        classifier: &mut PrologueClassifier,
+    ) {
+        for sample in samples {
+            let native_frames = sample.native_frames();
+            // A leaf frame that lost its native symbol to the build filter isn't counted, but its callers still are.
+            let leaf = native_frames
+                .first()
+                .filter(|leaf| leaf.symbol_name.is_some());
+            if let (Some(name), Some(leaf)) = (sample.symbol_name(), leaf) {
+                let position = classifier.classify(sample);
+                let function = self.function_mut(name);
+                if position == FramePosition::Prologue {
+                    function.add_head_samples(sample.weight);
+                }
+                let (callsites, location) = frame_locations(sample, leaf);
+                function.add_inlined_body_samples(&callsites, location, sample.weight);
+            }
+            self.add_call_targets(sample, &native_frames);
+        }
+    }
+
+    fn add_call_targets(&mut self, sample: &TransposedSample, native_frames: &[NativeFrame]) {
+        for pair in native_frames.windows(2) {
+            let (callee, caller) = (&pair[0], &pair[1]);
+            let (callee_name, caller_name) = match (callee.symbol_name, caller.symbol_name) {
+                (Some(callee_name), Some(caller_name)) => (callee_name, caller_name),
+                _ => continue,
+            };
+            // The call is made from the innermost function that has been inlined into the caller.
+            let (callsites, location) = frame_locations(sample, caller);
+            self.function_mut(caller_name)
+                .add_inlined_call_target_samples(&callsites, location, callee_name, sample.weight);
+        }
+    }
+
+    /// Add the samples of another profile, multiplied by `weight`.
+    pub fn merge(&mut self, other: &SampleProfile, weight: u64) {
+        for function in other.functions.values() {
+            self.function_mut(&function.name).merge(function, weight);
+        }
+    }
+
+    /// Remove every function, call target and inlined callee whose name isn't an assembler name, as compilers could never match them, and return what was removed, so that it can be reported.
+    /// This should be done before writing a profile out, as the writers write names exactly as they are.
+    /// Functions that were symbolicated with Breakpad symbol files, which only have demangled names for C++ functions, are the usual reason for removals.
+    pub fn remove_demangled_names(&mut self) -> RemovedNames {
+        let mut removed = RemovedNames::default();
+        let (kept, dropped): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.functions)
+            .into_iter()
+            .partition(|(name, _)| is_assembler_name(name));
+        self.functions = kept;
+        for (name, function) in dropped {
+            removed.samples = removed.samples.saturating_add(function.total_samples);
+            removed.names.insert(name);
+        }
+        for function in self.functions.values_mut() {
+            function.remove_demangled_names(&mut removed.names);
+        }
+        removed
+    }
+
+    /// Every name that the functions of this profile refer to, which is what the binary formats put in their name tables.
+    pub fn names(&self) -> BTreeSet<&str> {
+        let mut names = BTreeSet::new();
+        for function in self.functions.values() {
+            function.collect_names(&mut names);
+        }
+        names
+    }
+
+    /// Return the functions in this profile, ordered by decreasing total samples (and then by name), which is the order that LLVM's own writers use.
+    pub fn sorted_functions(&self) -> Vec<&FunctionSamples> {
+        let mut functions: Vec<&FunctionSamples> = self.functions.values().collect();
+        functions.sort_by(|a, b| {
+            b.total_samples
+                .cmp(&a.total_samples)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        functions
+    }
+}
+
+/// Find the chain of inlined callsites through a native frame, and the location of the frame within its innermost function.
+/// The outer frame's line is the line of the call to the first inlined function, whose frame has the line of the call to the next one, and so on, until the innermost frame, whose line is where the sample was taken.
+fn frame_locations<'a>(
+    sample: &TransposedSample<'a>,
+    native_frame: &NativeFrame<'a>,
+) -> (Vec<(LineLocation, &'a str)>, LineLocation) {
+    let mut callsites = vec![];
+    let mut line = native_frame.frame.line;
+    let mut start_line = sample.function_start_line(&native_frame.frame);
+    for frame in &native_frame.inlined_frames {
+        if let Some(name) = sample.function_name(frame) {
+            callsites.push((LineLocation::from_lines(line, start_line), name));
+            line = frame.line;
+            start_line = sample.function_start_line(frame);
+        }
+    }
+    (callsites, LineLocation::from_lines(line, start_line))
+}
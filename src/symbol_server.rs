@@ -0,0 +1,167 @@
+// A client for Breakpad/Tecken-style symbol servers, such as the one at https://symbols.mozilla.org/.
+// Symbol servers store files under `<debugName>/<breakpadId>/<fileName>`, so we mirror that layout in a local cache directory, which means that each file only ever needs to be downloaded once.
+// Debug names and breakpad IDs come from profiles, which we can't trust, so they are checked before they are used in a path or a url.
+
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+use crate::error::{Error, Result};
+
+pub const MOZILLA_SYMBOL_SERVER: &str = "https://symbols.mozilla.org/";
+
+/// The name of the Breakpad symbol file for a library, given its debug name.
+/// Windows debug names refer to the `.pdb` file, which is replaced by the `.sym` extension. All other platforms append `.sym` to the debug name.
+pub fn breakpad_symbol_file_name(debug_name: &str) -> String {
+    match debug_name.strip_suffix(".pdb") {
+        Some(stem) => format!("{}.sym", stem),
+        None => format!("{}.sym", debug_name),
+    }
+}
+
+/// Whether a debug name or file name can be used as a single component of a path or url, without leading anywhere else.
+fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\', '\0']) && !name.contains("..")
+}
+
+/// Whether an ID is a breakpad ID, i.e. a debug ID as 32 uppercase hex digits, followed by its age in (up to 8) more.
+fn is_valid_breakpad_id(breakpad_id: &str) -> bool {
+    (33..=40).contains(&breakpad_id.len())
+        && breakpad_id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+}
+
+/// Check that the location of a file on a symbol server can't lead out of its library's directory, either on the server or in our cache.
+fn check_file_location(debug_name: &str, breakpad_id: &str, file_name: &str) -> Result<()> {
+    if is_valid_file_name(debug_name)
+        && is_valid_breakpad_id(breakpad_id)
+        && is_valid_file_name(file_name)
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidSymbolFileLocation {
+            debug_name: debug_name.to_string(),
+            breakpad_id: breakpad_id.to_string(),
+            file_name: file_name.to_string(),
+        })
+    }
+}
+
+pub struct SymbolServer {
+    base_url: Url,
+    cache_dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl SymbolServer {
+    /// Create a client for the symbol server at `base_url`, which caches downloaded files under `cache_dir`.
+    pub fn new<P: Into<PathBuf>>(base_url: &str, cache_dir: P) -> Result<SymbolServer> {
+        // Make sure that the base url is treated as a directory when we join paths onto it.
+        let base_url = if base_url.ends_with('/') {
+            Url::parse(base_url)?
+        } else {
+            Url::parse(&format!("{}/", base_url))?
+        };
+        if base_url.cannot_be_a_base() {
+            return Err(Error::InvalidUrl(
+                url::ParseError::RelativeUrlWithCannotBeABaseBase,
+            ));
+        }
+        Ok(SymbolServer {
+            base_url,
+            cache_dir: cache_dir.into(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Create a client for Mozilla's symbol server.
+    pub fn mozilla<P: Into<PathBuf>>(cache_dir: P) -> SymbolServer {
+        // We can call unwrap here "safely" as the URL is static (i.e. we've pre-verified it).
+        SymbolServer::new(MOZILLA_SYMBOL_SERVER, cache_dir).unwrap()
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// The path at which a file from the server is stored in the local cache.
+    pub fn cache_path(
+        &self,
+        debug_name: &str,
+        breakpad_id: &str,
+        file_name: &str,
+    ) -> Result<PathBuf> {
+        check_file_location(debug_name, breakpad_id, file_name)?;
+        Ok(self
+            .cache_dir
+            .join(debug_name)
+            .join(breakpad_id)
+            .join(file_name))
+    }
+
+    /// The url of a file on the server. Each part of the location is percent-encoded as a single path segment.
+    pub fn url(&self, debug_name: &str, breakpad_id: &str, file_name: &str) -> Result<Url> {
+        check_file_location(debug_name, breakpad_id, file_name)?;
+        let mut url = self.base_url.clone();
+        // We can call unwrap here "safely" as `new` checked that the base url has a path.
+        url.path_segments_mut().unwrap().pop_if_empty().extend([
+            debug_name,
+            breakpad_id,
+            file_name,
+        ]);
+        Ok(url)
+    }
+
+    /// Fetch a file from the symbol server, or from the local cache if it has been fetched before.
+    /// This can be used for Breakpad symbol files, or for native debug files, such as `XUL.dSYM.tar.bz2` or `libxul.so.dbg.gz`.
+    /// Locations that could lead out of the library's directory are an error, and nothing is fetched for them.
+    pub async fn fetch_file(
+        &self,
+        debug_name: &str,
+        breakpad_id: &str,
+        file_name: &str,
+    ) -> Result<PathBuf> {
+        let cache_path = self.cache_path(debug_name, breakpad_id, file_name)?;
+        if cache_path.is_file() {
+            return Ok(cache_path);
+        }
+
+        let url = self.url(debug_name, breakpad_id, file_name)?;
+        let response = self.client.get(url.clone()).send().await?;
+        if !response.status().is_success() {
+            return Err(Error::SymbolFetch {
+                url,
+                status: response.status(),
+            });
+        }
+        let data = response.bytes().await?;
+
+        // Write to a temporary file first, so that an interrupted download never leaves a truncated file in the cache.
+        let partial_path = cache_path.with_extension("partial");
+        let write = async {
+            // The cache path always has a parent, as it is built from the cache directory.
+            tokio::fs::create_dir_all(cache_path.parent().unwrap()).await?;
+            tokio::fs::write(&partial_path, &data).await?;
+            tokio::fs::rename(&partial_path, &cache_path).await
+        };
+        write
+            .await
+            .map_err(|e| Error::from(e).with_path(&cache_path))?;
+        Ok(cache_path)
+    }
+
+    /// Fetch the Breakpad symbol file for a library.
+    pub async fn fetch_breakpad_symbols(
+        &self,
+        debug_name: &str,
+        breakpad_id: &str,
+    ) -> Result<PathBuf> {
+        self.fetch_file(
+            debug_name,
+            breakpad_id,
+            &breakpad_symbol_file_name(debug_name),
+        )
+        .await
+    }
+}
@@ -0,0 +1,89 @@
+// A common interface over the different places that we can get symbols from.
+// wholesym handles native binaries and debug files found on this machine, and we parse Breakpad symbol files (e.g. from a symbol server) ourselves.
+
+use crate::breakpad::BreakpadSymbols;
+use crate::fx_processed_profile::table_address::Address;
+
+/// A single frame of source information for an address, in the same style as wholesym's `FrameDebugInfo`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceFrame {
+    pub function: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// The result of looking up an address: the symbol that contains it, and the source frames at that address, ordered from the innermost inlined function to the outer function.
+/// `start_line` is the line of the symbol's first instruction, which we use in place of the line that the function is declared on, as neither source records that.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SymbolLookup {
+    pub name: String,
+    pub address: Address,
+    pub size: Option<u64>,
+    pub start_line: Option<u32>,
+    pub frames: Vec<SourceFrame>,
+}
+
+pub enum SymbolSource {
+    SymbolMap(wholesym::SymbolMap),
+    Breakpad(BreakpadSymbols),
+}
+
+impl SymbolSource {
+    pub fn symbol_count(&self) -> usize {
+        match self {
+            SymbolSource::SymbolMap(map) => map.symbol_count(),
+            SymbolSource::Breakpad(symbols) => symbols.symbol_count(),
+        }
+    }
+
+    /// A description of where the symbols came from, for diagnostics.
+    pub fn origin(&self) -> String {
+        match self {
+            SymbolSource::SymbolMap(map) => format!("{:?}", map.symbol_file_origin()),
+            SymbolSource::Breakpad(symbols) => match &symbols.module {
+                Some(module) => format!("Breakpad symbols for {} {}", module.name, module.id),
+                None => "Breakpad symbols".to_string(),
+            },
+        }
+    }
+
+    /// Resolve an address relative to the library's base address.
+    pub fn lookup(&self, address: Address) -> Option<SymbolLookup> {
+        match self {
+            SymbolSource::SymbolMap(map) => {
+                let info = map.lookup_relative_address(u32::try_from(address).ok()?)?;
+                let mut frames: Vec<SourceFrame> = info
+                    .frames
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|frame| SourceFrame {
+                        function: frame.function.unwrap_or_else(|| info.symbol.name.clone()),
+                        file: frame.file_path.map(|path| path.raw_path().to_string()),
+                        line: frame.line_number,
+                    })
+                    .collect();
+                // Without debug information, the symbol itself is the only frame.
+                if frames.is_empty() {
+                    frames.push(SourceFrame {
+                        function: info.symbol.name.clone(),
+                        file: None,
+                        line: None,
+                    });
+                }
+                // The outermost frame at the start of the symbol is the function itself, as nothing can have been inlined before its first instruction.
+                let start_line = map
+                    .lookup_relative_address(info.symbol.address)
+                    .and_then(|start| start.frames)
+                    .and_then(|frames| frames.last().and_then(|frame| frame.line_number));
+                Some(SymbolLookup {
+                    name: info.symbol.name,
+                    address: info.symbol.address as Address,
+                    size: info.symbol.size.map(|size| size as u64),
+                    start_line,
+                    frames,
+                })
+            }
+            SymbolSource::Breakpad(symbols) => symbols.lookup(address),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+// Rewrite a processed profile with the symbols that we have found for its libraries.
+// This follows the same approach as the Firefox profiler's own symbolication (see [symbolication.js](https://github.com/firefox-devtools/profiler/blob/main/src/profile-logic/symbolication.js)): every native frame is resolved to a function, and frames which resolve to the same function share a single entry in the func table.
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::fx_processed_profile::table_address::{Address, TableAddress};
+use crate::fx_processed_profile::{
+    FrameTableEntry, FuncTableEntry, IndexIntoFrameTable, IndexIntoFuncTable, IndexIntoLibs,
+    IndexIntoNativeSymbolTable, IndexIntoResourceTable, IndexIntoStackTable, IndexIntoStringTable,
+    NativeSymbolTableEntry, Profile, StackTableEntry, Thread,
+};
+use crate::profile_table_iterator::TableLookup;
+use crate::symbol_source::{SymbolLookup, SymbolSource};
+
+/// Adds strings to a thread's string table, reusing any existing copies of a string.
+struct StringInterner {
+    indices: FxHashMap<String, IndexIntoStringTable>,
+}
+
+impl StringInterner {
+    fn new(strings: &[String]) -> StringInterner {
+        let mut indices = FxHashMap::default();
+        for (ix, s) in strings.iter().enumerate() {
+            indices
+                .entry(s.clone())
+                .or_insert(ix as IndexIntoStringTable);
+        }
+        StringInterner { indices }
+    }
+
+    fn intern(&mut self, strings: &mut Vec<String>, s: &str) -> IndexIntoStringTable {
+        if let Some(&ix) = self.indices.get(s) {
+            return ix;
+        }
+        strings.push(s.to_string());
+        let ix = (strings.len() - 1) as IndexIntoStringTable;
+        self.indices.insert(s.to_string(), ix);
+        ix
+    }
+}
+
+struct ThreadSymbolicator<'a> {
+    sources: &'a [Option<SymbolSource>],
+    strings: StringInterner,
+    native_symbols: FxHashMap<(IndexIntoLibs, Address), IndexIntoNativeSymbolTable>,
+    funcs: FxHashMap<(IndexIntoResourceTable, IndexIntoStringTable), IndexIntoFuncTable>,
+    // The frames that have been added for inlined functions, keyed by the frame of their outermost function.
+    inlined_frames: FxHashMap<IndexIntoFrameTable, Vec<IndexIntoFrameTable>>,
+    // The frames whose inlined functions were already expanded by an earlier symbolication, which must not be expanded again.
+    expanded_frames: FxHashSet<IndexIntoFrameTable>,
+}
+
+impl<'a> ThreadSymbolicator<'a> {
+    fn new(thread: &Thread, sources: &'a [Option<SymbolSource>]) -> ThreadSymbolicator<'a> {
+        let native_symbols = thread
+            .nativeSymbols
+            .iter()
+            .enumerate()
+            .map(|(ix, entry)| {
+                (
+                    (entry.libIndex, entry.address),
+                    ix as IndexIntoNativeSymbolTable,
+                )
+            })
+            .collect();
+        let mut funcs = FxHashMap::default();
+        for ix in 0..thread.funcTable.length() {
+            if !thread.funcTable.isJS[ix] {
+                funcs
+                    .entry((thread.funcTable.resource[ix], thread.funcTable.name[ix]))
+                    .or_insert(ix as IndexIntoFuncTable);
+            }
+        }
+        ThreadSymbolicator {
+            sources,
+            strings: StringInterner::new(&thread.stringTable),
+            native_symbols,
+            funcs,
+            inlined_frames: FxHashMap::default(),
+            expanded_frames: ThreadSymbolicator::expanded_frames(thread),
+        }
+    }
+
+    /// Find the frames that already have inlined frames, from the stacks of inlined frames that were added as children of their stacks.
+    fn expanded_frames(thread: &Thread) -> FxHashSet<IndexIntoFrameTable> {
+        let stacks = &thread.stackTable;
+        (0..stacks.length())
+            .filter(|&ix| thread.frameTable.inlineDepth[stacks.frame[ix] as usize] == 1)
+            .filter_map(|ix| stacks.prefix[ix])
+            .map(|prefix| stacks.frame[prefix as usize])
+            .collect()
+    }
+
+    fn lookup(&self, lib: IndexIntoLibs, address: Address) -> Option<SymbolLookup> {
+        self.sources.get(lib as usize)?.as_ref()?.lookup(address)
+    }
+
+    /// Find the library that a frame belongs to, either through its native symbol, or through the resource of its function.
+    fn frame_lib(thread: &Thread, frame_ix: usize) -> Option<IndexIntoLibs> {
+        if let Some(symbol) = thread.frameTable.nativeSymbol[frame_ix] {
+            return Some(thread.nativeSymbols.libIndex[symbol as usize]);
+        }
+        let func = thread.frameTable.func[frame_ix]?;
+        match thread.funcTable.resource[func as usize] {
+            TableAddress::Address(resource) => thread.resourceTable.lib[resource as usize],
+            _ => None,
+        }
+    }
+
+    fn symbolicate_native_symbols(&mut self, thread: &mut Thread) {
+        for ix in 0..thread.nativeSymbols.length() {
+            let entry = thread.nativeSymbols.lookup(ix);
+            if let Some(symbol) = self.lookup(entry.libIndex, entry.address) {
+                thread.nativeSymbols.name[ix] =
+                    self.strings.intern(&mut thread.stringTable, &symbol.name);
+                thread.nativeSymbols.functionSize[ix] = symbol
+                    .size
+                    .and_then(|size| u32::try_from(size).ok())
+                    .or(entry.functionSize);
+            }
+        }
+    }
+
+    fn native_symbol(
+        &mut self,
+        thread: &mut Thread,
+        lib: IndexIntoLibs,
+        symbol: &SymbolLookup,
+    ) -> IndexIntoNativeSymbolTable {
+        if let Some(&ix) = self.native_symbols.get(&(lib, symbol.address)) {
+            return ix;
+        }
+        let name = self.strings.intern(&mut thread.stringTable, &symbol.name);
+        let ix = thread.nativeSymbols.push(NativeSymbolTableEntry {
+            libIndex: lib,
+            address: symbol.address,
+            name,
+            functionSize: symbol.size.and_then(|size| u32::try_from(size).ok()),
+        });
+        self.native_symbols.insert((lib, symbol.address), ix);
+        ix
+    }
+
+    /// Find or create the function with a given name. `line_number` is the line that the function starts on, which we only know for outer functions, so a function that was first seen inlined has its line filled in later.
+    fn func(
+        &mut self,
+        thread: &mut Thread,
+        resource: IndexIntoResourceTable,
+        name: &str,
+        file: Option<&str>,
+        line_number: Option<u32>,
+    ) -> IndexIntoFuncTable {
+        let name = self.strings.intern(&mut thread.stringTable, name);
+        if let Some(&ix) = self.funcs.get(&(resource, name)) {
+            let existing = &mut thread.funcTable.lineNumber[ix as usize];
+            if existing.is_none() {
+                *existing = line_number;
+            }
+            return ix;
+        }
+        let file_name = file.map(|f| self.strings.intern(&mut thread.stringTable, f));
+        let ix = thread.funcTable.push(FuncTableEntry {
+            name,
+            isJS: false,
+            relevantForJS: false,
+            resource,
+            fileName: file_name,
+            lineNumber: line_number,
+            columnNumber: None,
+        });
+        self.funcs.insert((resource, name), ix);
+        ix
+    }
+
+    fn symbolicate_frames(&mut self, thread: &mut Thread) {
+        for ix in 0..thread.frameTable.length() {
+            // Frames for inlined functions, and the frames that they were inlined into, have already been expanded by an earlier symbolication.
+            if thread.frameTable.inlineDepth[ix] > 0
+                || self.expanded_frames.contains(&(ix as IndexIntoFrameTable))
+            {
+                continue;
+            }
+            let address = match thread.frameTable.address[ix] {
+                TableAddress::Address(a) => a,
+                _ => continue,
+            };
+            let lib = match ThreadSymbolicator::frame_lib(thread, ix) {
+                Some(lib) => lib,
+                None => continue,
+            };
+            let symbol = match self.lookup(lib, address) {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+            let resource = thread.frameTable.func[ix]
+                .map(|func| thread.funcTable.resource[func as usize])
+                .or_else(|| thread.resourceTable.resource_for_lib(lib))
+                .unwrap_or(TableAddress::Base);
+
+            // The existing frame becomes the outermost function at this address, so its location is the last of the source frames.
+            let outer = symbol.frames.last();
+            let file = outer.and_then(|frame| frame.file.as_deref());
+            let func = self.func(thread, resource, &symbol.name, file, symbol.start_line);
+            let native_symbol = self.native_symbol(thread, lib, &symbol);
+
+            thread.frameTable.func[ix] = Some(func);
+            thread.frameTable.nativeSymbol[ix] = Some(native_symbol);
+            thread.frameTable.line[ix] = outer.and_then(|frame| frame.line);
+            thread.frameTable.inlineDepth[ix] = 0;
+
+            // Every function that has been inlined at this address gets a new frame, with an increasing inline depth.
+            let frame = thread.frameTable.lookup(ix);
+            let inlined_frames: Vec<IndexIntoFrameTable> = symbol
+                .frames
+                .iter()
+                .rev()
+                .skip(1)
+                .enumerate()
+                .map(|(depth, inlined)| {
+                    let func = self.func(
+                        thread,
+                        resource,
+                        &inlined.function,
+                        inlined.file.as_deref(),
+                        None,
+                    );
+                    thread.frameTable.push(FrameTableEntry {
+                        inlineDepth: depth as i32 + 1,
+                        func: Some(func),
+                        line: inlined.line,
+                        column: None,
+                        ..frame
+                    })
+                })
+                .collect();
+            if !inlined_frames.is_empty() {
+                self.inlined_frames
+                    .insert(ix as IndexIntoFrameTable, inlined_frames);
+            }
+        }
+    }
+
+    /// Add the inlined frames to the stack table.
+    /// Every stack whose frame has inlined frames gets a chain of new stacks appended to it, one per inlined frame. Stacks and samples that pointed at the original stack are then moved to the innermost stack of that chain. This keeps the indices of all existing stacks the same.
+    fn expand_inlined_stacks(&self, thread: &mut Thread) {
+        if self.inlined_frames.is_empty() {
+            return;
+        }
+        let stack_count = thread.stackTable.length();
+        let mut innermost: Vec<IndexIntoStackTable> =
+            (0..stack_count as IndexIntoStackTable).collect();
+        for ix in 0..stack_count {
+            let stack = thread.stackTable.lookup(ix);
+            if let Some(frames) = self.inlined_frames.get(&stack.frame) {
+                let mut prefix = ix as IndexIntoStackTable;
+                for &frame in frames {
+                    prefix = thread.stackTable.push(StackTableEntry {
+                        frame,
+                        category: stack.category,
+                        prefix: Some(prefix),
+                    });
+                }
+                innermost[ix] = prefix;
+            }
+        }
+
+        let remap = |stack: &mut Option<IndexIntoStackTable>| {
+            if let Some(ix) = stack {
+                if let Some(&inner) = innermost.get(*ix as usize) {
+                    *ix = inner;
+                }
+            }
+        };
+        thread.stackTable.prefix[..stack_count]
+            .iter_mut()
+            .for_each(remap);
+        thread.samples.stack.iter_mut().for_each(remap);
+    }
+}
+
+fn symbolicate_thread(thread: &mut Thread, sources: &[Option<SymbolSource>]) {
+    let mut symbolicator = ThreadSymbolicator::new(thread, sources);
+    symbolicator.symbolicate_native_symbols(thread);
+    symbolicator.symbolicate_frames(thread);
+    symbolicator.expand_inlined_stacks(thread);
+}
+
+/// The libraries that native frames of a profile belong to, but that have no symbol source, so that their frames can't be symbolicated.
+pub fn unresolved_libs(profile: &Profile, sources: &[Option<SymbolSource>]) -> Vec<IndexIntoLibs> {
+    let mut libs: Vec<IndexIntoLibs> = profile
+        .threads
+        .iter()
+        .flat_map(|thread| {
+            (0..thread.frameTable.length())
+                .filter(|&ix| matches!(thread.frameTable.address[ix], TableAddress::Address(_)))
+                .filter_map(move |ix| ThreadSymbolicator::frame_lib(thread, ix))
+        })
+        .filter(|&lib| sources.get(lib as usize).and_then(Option::as_ref).is_none())
+        .collect();
+    libs.sort_unstable();
+    libs.dedup();
+    libs
+}
+
+/// Symbolicate every thread of a profile, given a symbol source for each of its libraries (indexed in the same way as `Profile.libs`).
+/// Frames in libraries without a symbol source are left untouched.
+/// The profile's tables must be consistent, which `fx_import::check_tables` makes sure of.
+/// The profile is only marked as symbolicated if every library with native frames had a symbol source, so that a partly symbolicated profile can be symbolicated again (by us, or by the Firefox Profiler) once the missing symbols are available.
+pub fn symbolicate_profile(mut profile: Profile, sources: &[Option<SymbolSource>]) -> Profile {
+    let complete = unresolved_libs(&profile, sources).is_empty();
+    for thread in profile.threads.iter_mut() {
+        symbolicate_thread(thread, sources);
+    }
+    if complete {
+        profile.meta.symbolicated = Some(true);
+    }
+    profile
+}
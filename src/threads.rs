@@ -0,0 +1,148 @@
+// Selecting which threads of a profile to take samples from, so that specialised PGO profiles can be built, such as one of only the content processes.
+// Selectors match a single property of a thread, and combine into more specific ones, such as "the main thread of tab processes".
+
+use std::ops::Not;
+
+use crate::error::Error;
+use crate::fx_processed_profile::{Pid, ProcessType, Thread};
+
+/// A test of a thread's properties.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ThreadSelector {
+    /// Threads with this name, such as `GeckoMain` or `Renderer`.
+    Name(String),
+    ProcessType(ProcessType),
+    /// The main thread of each process.
+    MainThread,
+    /// Threads of processes with this name, such as `Isolated Web Content`.
+    ProcessName(String),
+    Pid(Pid),
+    /// Threads that match every one of these selectors.
+    All(Vec<ThreadSelector>),
+    /// Threads that match any of these selectors.
+    Any(Vec<ThreadSelector>),
+    /// Threads that don't match this selector.
+    Not(Box<ThreadSelector>),
+}
+
+impl ThreadSelector {
+    pub fn name<S: Into<String>>(name: S) -> ThreadSelector {
+        ThreadSelector::Name(name.into())
+    }
+
+    pub fn process_name<S: Into<String>>(name: S) -> ThreadSelector {
+        ThreadSelector::ProcessName(name.into())
+    }
+
+    /// Threads that match both this selector and another.
+    pub fn and(self, other: ThreadSelector) -> ThreadSelector {
+        match self {
+            ThreadSelector::All(mut selectors) => {
+                selectors.push(other);
+                ThreadSelector::All(selectors)
+            }
+            selector => ThreadSelector::All(vec![selector, other]),
+        }
+    }
+
+    /// Threads that match either this selector or another.
+    pub fn or(self, other: ThreadSelector) -> ThreadSelector {
+        match self {
+            ThreadSelector::Any(mut selectors) => {
+                selectors.push(other);
+                ThreadSelector::Any(selectors)
+            }
+            selector => ThreadSelector::Any(vec![selector, other]),
+        }
+    }
+
+    pub fn matches(&self, thread: &Thread) -> bool {
+        match self {
+            ThreadSelector::Name(name) => thread.name == *name,
+            ThreadSelector::ProcessType(process_type) => thread.processType == *process_type,
+            ThreadSelector::MainThread => thread.isMainThread,
+            ThreadSelector::ProcessName(name) => thread.processName.as_ref() == Some(name),
+            ThreadSelector::Pid(pid) => thread.pid == *pid,
+            ThreadSelector::All(selectors) => selectors.iter().all(|s| s.matches(thread)),
+            ThreadSelector::Any(selectors) => selectors.iter().any(|s| s.matches(thread)),
+            ThreadSelector::Not(selector) => !selector.matches(thread),
+        }
+    }
+
+    /// Parse a selector from comma-separated `<property>=<value>` terms, all of which a thread must match, e.g. `name=GeckoMain,process-type=tab`.
+    /// The properties are `name`, `process-type`, `process-name`, `pid` and `main-thread` (which may be given without a value, or as `main-thread=false`). A `!` before a term negates it.
+    pub fn parse(selector: &str) -> Result<ThreadSelector, Error> {
+        let invalid = || Error::InvalidThreadSelector(selector.to_string());
+        let mut terms = vec![];
+        for term in selector.split(',') {
+            let (negated, term) = match term.trim().strip_prefix('!') {
+                Some(term) => (true, term),
+                None => (false, term.trim()),
+            };
+            let (property, value) = match term.split_once('=') {
+                Some((property, value)) => (property.trim(), Some(value.trim())),
+                None => (term, None),
+            };
+            let term = match (property, value) {
+                ("name", Some(name)) => ThreadSelector::name(name),
+                ("process-name", Some(name)) => ThreadSelector::process_name(name),
+                ("pid", Some(pid)) => ThreadSelector::Pid(pid.to_string()),
+                ("process-type", Some(process_type)) => ThreadSelector::ProcessType(
+                    parse_process_type(process_type).ok_or_else(invalid)?,
+                ),
+                ("main-thread", None | Some("true")) => ThreadSelector::MainThread,
+                ("main-thread", Some("false")) => !ThreadSelector::MainThread,
+                _ => return Err(invalid()),
+            };
+            terms.push(if negated { !term } else { term });
+        }
+        Ok(match terms.len() {
+            1 => terms.remove(0),
+            _ => ThreadSelector::All(terms),
+        })
+    }
+}
+
+impl Not for ThreadSelector {
+    type Output = ThreadSelector;
+
+    fn not(self) -> ThreadSelector {
+        match self {
+            ThreadSelector::Not(selector) => *selector,
+            selector => ThreadSelector::Not(Box::new(selector)),
+        }
+    }
+}
+
+/// Process types are named as they are in profiles, e.g. `tab` or `gpu`. Types that we don't know of aren't accepted, as they could never match.
+fn parse_process_type(process_type: &str) -> Option<ProcessType> {
+    serde_json::from_value(serde_json::Value::from(process_type))
+        .ok()
+        .filter(|process_type| *process_type != ProcessType::Other)
+}
+
+/// The threads that we want to keep samples from: those that match any of the included selectors (or every thread, if there are none), and none of the excluded ones.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ThreadFilter {
+    include: Vec<ThreadSelector>,
+    exclude: Vec<ThreadSelector>,
+}
+
+impl ThreadFilter {
+    pub fn include(&mut self, selector: ThreadSelector) {
+        self.include.push(selector);
+    }
+
+    pub fn exclude(&mut self, selector: ThreadSelector) {
+        self.exclude.push(selector);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, thread: &Thread) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|s| s.matches(thread));
+        included && !self.exclude.iter().any(|s| s.matches(thread))
+    }
+}
@@ -0,0 +1,106 @@
+// Restricting aggregation to part of a profile, such as the range that a user selected in the Firefox Profiler before sharing it, so that startup and shutdown don't drown out the work that was being profiled.
+// Sample times are in milliseconds since `meta.startTime`, but ranges are given either from the profile's zero (the time that the Firefox Profiler shows as zero), or as Unix timestamps.
+
+use crate::error::Error;
+use crate::fx_processed_profile::{Milliseconds, Profile, StartEndRange, Thread};
+
+/// What the bounds of a `TimeRange` are measured from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TimeOrigin {
+    /// The time that the Firefox Profiler shows as zero: the start of profiling, or the profile's earliest sample in profiles that don't record it.
+    #[default]
+    ProfileStart,
+    /// The Unix epoch, so that the bounds are timestamps, like `meta.startTime`.
+    UnixEpoch,
+}
+
+/// A span of time in milliseconds, which may be open at either end. Ranges include their start, but not their end.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct TimeRange {
+    pub start: Option<Milliseconds>,
+    pub end: Option<Milliseconds>,
+    pub origin: TimeOrigin,
+}
+
+impl TimeRange {
+    /// Parse a range of the form `<start>..<end>`, in milliseconds, where either bound can be left out, e.g. `1500..` for everything after the first 1.5s.
+    pub fn parse(range: &str, origin: TimeOrigin) -> Result<TimeRange, Error> {
+        let invalid = || Error::InvalidTimeRange(range.to_string());
+        let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+        let bound = |bound: &str| match bound.trim() {
+            "" => Ok(None),
+            bound => bound.parse::<f64>().map(Some).map_err(|_| invalid()),
+        };
+        let (start, end) = (bound(start)?, bound(end)?);
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(invalid());
+            }
+        }
+        Ok(TimeRange { start, end, origin })
+    }
+
+    /// Parse the committed ranges of a Firefox Profiler URL (its `range` parameter), such as `1843m1500~1900m200`.
+    /// Each range is a start and a duration, in the unit given between them (`m` for milliseconds, `u` for microseconds or `n` for nanoseconds), from the profile's zero. Ranges are nested, so the last one is the one that was selected.
+    pub fn from_committed_ranges(ranges: &str) -> Result<TimeRange, Error> {
+        let invalid = || Error::InvalidTimeRange(ranges.to_string());
+        let last = ranges.rsplit('~').next().ok_or_else(invalid)?;
+        let unit_at = last
+            .find(|c: char| c.is_ascii_alphabetic())
+            .ok_or_else(invalid)?;
+        let per_millisecond = match &last[unit_at..unit_at + 1] {
+            "m" => 1.0,
+            "u" => 1_000.0,
+            "n" => 1_000_000.0,
+            _ => return Err(invalid()),
+        };
+        let start = last[..unit_at].parse::<u64>().map_err(|_| invalid())? as f64;
+        let duration = last[unit_at + 1..].parse::<u64>().map_err(|_| invalid())? as f64;
+        Ok(TimeRange {
+            start: Some(start / per_millisecond),
+            end: Some((start + duration) / per_millisecond),
+            origin: TimeOrigin::ProfileStart,
+        })
+    }
+
+    /// The bounds of this range in the times of a profile's samples. Open bounds are infinite.
+    pub fn resolve(&self, profile: &Profile) -> StartEndRange {
+        let offset = match self.origin {
+            TimeOrigin::ProfileStart => profile_zero(profile),
+            TimeOrigin::UnixEpoch => -profile.meta.startTime,
+        };
+        StartEndRange {
+            start: self.start.map_or(f64::NEG_INFINITY, |start| start + offset),
+            end: self.end.map_or(f64::INFINITY, |end| end + offset),
+        }
+    }
+}
+
+impl StartEndRange {
+    pub fn contains(&self, time: Milliseconds) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+/// The time that the Firefox Profiler shows as zero, which is when profiling started. Older profiles don't record that, and the profiler uses the earliest sample of any thread instead.
+pub fn profile_zero(profile: &Profile) -> Milliseconds {
+    profile.meta.profilingStartTime.unwrap_or_else(|| {
+        profile
+            .threads
+            .iter()
+            .filter_map(|thread| thread.samples.time.first().copied())
+            .reduce(f64::min)
+            .unwrap_or(0.0)
+    })
+}
+
+/// The time that a thread was registered with the profiler for, falling back to the lifetime of its process. Samples outside it were taken while the thread was starting up or shutting down.
+pub fn thread_lifetime(thread: &Thread) -> StartEndRange {
+    StartEndRange {
+        start: thread.registerTime.unwrap_or(thread.processStartupTime),
+        end: thread
+            .unregisterTime
+            .or(thread.processShutdownTime)
+            .unwrap_or(f64::INFINITY),
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
     fx_processed_profile::{
-        self, FrameTable, IndexIntoFrameTable, NativeSymbolTable, NativeSymbolTableEntry,
-        SamplesTable, StackTable,
+        self, table_address::TableAddress, FrameTable, FrameTableEntry, FuncTable,
+        IndexIntoFrameTable, NativeSymbolTable, NativeSymbolTableEntry, PausedRange, ProfileMeta,
+        SamplesTable, StackTable, ThreadCPUDeltaUnit, Weight, WeightType,
     },
+    libraries::BuildFilter,
     profile_table_iterator::TableLookup,
+    threads::ThreadFilter,
+    time_range::{self, TimeRange},
 };
 
 /// A ThreadTables struct is a collection of references to thread-specific tables.
@@ -13,6 +17,7 @@ use crate::{
 pub struct ThreadTables<'a> {
     pub stack_table: &'a StackTable,
     pub frame_table: &'a FrameTable,
+    pub func_table: &'a FuncTable,
     pub string_table: &'a Vec<String>,
     pub symbol_table: &'a NativeSymbolTable,
 }
@@ -21,20 +26,299 @@ pub struct ThreadTables<'a> {
 /// We want it in this form so that we can iterate in a "flat" manner.
 pub struct TransposedSample<'a> {
     pub stack_frame: i64,
+    pub frame_address: TableAddress,
     pub symbol_table_entry: NativeSymbolTableEntry,
     pub sample_time: f64,
+    /// The number of samples that this sample stands for, which is more than one in profiles that have merged identical samples.
+    pub weight: u64,
     pub string_table_index: Option<i64>,
+    /// Every frame of the sample's stack, from the leaf (the frame of `stack_frame`) to the root.
+    pub frames: Vec<FrameTableEntry>,
     // lookup references, as these are thread specific, so we need to
     pub thread_tables: ThreadTables<'a>,
 }
 
-/// Perform a "flattening" operation 
+impl<'a> TransposedSample<'a> {
+    /// Look up the name of the native symbol for this sample in its thread's string table.
+    pub fn symbol_name(&self) -> Option<&'a str> {
+        self.thread_tables
+            .string_table
+            .get(self.symbol_table_entry.name as usize)
+            .map(|s| s.as_str())
+    }
+
+    /// Walk up the stack of this sample through the frames of inlined functions, and return their names, from the outermost inlined function inwards.
+    /// The function that they have all been inlined into is the native symbol of the sample.
+    pub fn inlined_function_names(&self) -> Vec<&'a str> {
+        let mut names: Vec<&'a str> = self
+            .frames
+            .iter()
+            .take_while(|frame| frame.inlineDepth > 0)
+            .filter_map(|frame| self.function_name(frame))
+            .collect();
+        names.reverse();
+        names
+    }
+
+    /// Look up the name of a frame's function, if it has one.
+    pub fn function_name(&self, frame: &FrameTableEntry) -> Option<&'a str> {
+        let tables = self.thread_tables;
+        frame
+            .func
+            .and_then(|func| tables.func_table.name.get(func as usize))
+            .and_then(|&name| tables.string_table.get(name as usize))
+            .map(|name| name.as_str())
+    }
+
+    /// Look up the name of a frame's native symbol, if it has one.
+    pub fn native_symbol_name(&self, frame: &FrameTableEntry) -> Option<&'a str> {
+        let tables = self.thread_tables;
+        frame
+            .nativeSymbol
+            .and_then(|symbol| tables.symbol_table.name.get(symbol as usize))
+            .and_then(|&name| tables.string_table.get(name as usize))
+            .map(|name| name.as_str())
+    }
+
+    /// Group the frames of this sample's stack by native frame, from the leaf to the root.
+    /// Frames of inlined functions come before (i.e. leafwards of) the frame that they were inlined into, so each native frame ends at a frame with an inline depth of zero. Inlined frames at the root with no outer frame are dropped.
+    pub fn native_frames(&self) -> Vec<NativeFrame<'a>> {
+        let mut native_frames = vec![];
+        let mut inlined = vec![];
+        for frame in &self.frames {
+            if frame.inlineDepth > 0 {
+                inlined.push(*frame);
+                continue;
+            }
+            inlined.reverse();
+            native_frames.push(NativeFrame {
+                frame: *frame,
+                symbol_name: self.native_symbol_name(frame),
+                inlined_frames: std::mem::take(&mut inlined),
+            });
+        }
+        native_frames
+    }
+
+    /// Look up the line that a frame's function starts on, if it is known.
+    pub fn function_start_line(&self, frame: &FrameTableEntry) -> Option<u32> {
+        frame
+            .func
+            .and_then(|func| self.thread_tables.func_table.lineNumber.get(func as usize))
+            .copied()
+            .flatten()
+    }
+
+    /// The frames that called into the leaf frame, from its direct caller to the root.
+    pub fn caller_frames(&self) -> &[FrameTableEntry] {
+        self.frames.get(1..).unwrap_or(&[])
+    }
+}
+
+/// A single frame of the native call stack (i.e. a single return address), along with the functions that have been inlined at that address.
+pub struct NativeFrame<'a> {
+    /// The outermost frame at this address, which is the one with an inline depth of zero.
+    pub frame: FrameTableEntry,
+    pub symbol_name: Option<&'a str>,
+    /// The frames of the functions that have been inlined at this address, from the outermost inlined function inwards.
+    pub inlined_frames: Vec<FrameTableEntry>,
+}
+
+/// What to do with the samples of threads whose weights aren't sample counts, such as the durations of tracing profiles, or the sizes of allocations.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum WeightPolicy {
+    /// Refuse to aggregate them, as their weights would skew the sample counts.
+    /// Transposition can't fail, so it leaves them out, but `aggregate_filtered_profile` reports them as an error.
+    #[default]
+    Reject,
+    /// Leave them out, and count them as discarded.
+    Skip,
+    /// Convert their weights into sample counts: traced durations are divided by the profile's sampling interval, and anything else (such as bytes) counts as a single sample.
+    Convert,
+}
+
+/// How to account for the time that threads spent off the CPU, such as waiting in `poll` or `futex`, using the CPU deltas of their samples.
+/// Samples without a CPU delta (including every sample of a profile without `meta.sampleUnits`) are counted as though their thread was busy.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CpuWeighting {
+    /// Count samples by wall-clock time, whether or not their thread was on the CPU.
+    #[default]
+    WallClock,
+    /// Drop samples whose thread was on the CPU for less than `threshold` of the time since its previous sample.
+    DropIdle { threshold: f64 },
+    /// Scale the weight of each sample by the fraction of the time since its previous sample that its thread was on the CPU, so that a sample of a thread that was busy throughout keeps its weight.
+    /// Scaled weights are rounded down, and the fractions of a sample that this leaves over are carried on to the thread's next sample, so that the thread's total is kept.
+    Scale,
+}
+
+impl CpuWeighting {
+    /// Weight a sample by the time that its thread spent on the CPU, or return `None` if it should be dropped as idle.
+    /// `carry` is the fraction of a sample that scaling has left over from the thread's earlier samples.
+    fn apply(self, weight: u64, cpu_fraction: Option<f64>, carry: &mut f64) -> Option<u64> {
+        match (self, cpu_fraction) {
+            (CpuWeighting::WallClock, _) => Some(weight),
+            (CpuWeighting::DropIdle { threshold }, Some(fraction)) if fraction < threshold => None,
+            (CpuWeighting::DropIdle { .. }, _) => Some(weight),
+            (CpuWeighting::Scale, Some(fraction)) if fraction <= 0.0 => None,
+            (CpuWeighting::Scale, fraction) => {
+                let exact = weight as f64 * fraction.unwrap_or(1.0) + *carry;
+                let scaled = exact.floor();
+                *carry = exact - scaled;
+                Some(scaled as u64)
+            }
+        }
+    }
+}
+
+/// The fraction of the time since each sample's previous sample (or of the sampling interval, for the first sample) that its thread spent on the CPU, if it is known.
+/// CPU deltas measured in cycles can't be converted into time, so they are compared with the busiest sample of the thread instead, which is taken to have been on the CPU throughout.
+pub fn cpu_fractions(samples: &SamplesTable, meta: &ProfileMeta) -> Vec<Option<f64>> {
+    let (deltas, units) = match (&samples.threadCPUDelta, &meta.sampleUnits) {
+        (Some(deltas), Some(units)) => (deltas, units),
+        _ => return vec![None; samples.time.len()],
+    };
+    let elapsed: Vec<f64> = samples
+        .time
+        .iter()
+        .enumerate()
+        .map(|(ix, &time)| match ix {
+            0 => meta.interval,
+            _ => time - samples.time[ix - 1],
+        })
+        .collect();
+    let delta_per_ms = match units.threadCPUDelta {
+        ThreadCPUDeltaUnit::Nanoseconds => 1_000_000.0,
+        ThreadCPUDeltaUnit::Microseconds => 1_000.0,
+        ThreadCPUDeltaUnit::VariableCPUCycles => deltas
+            .iter()
+            .zip(&elapsed)
+            .filter(|(_, &elapsed)| elapsed > 0.0)
+            .filter_map(|(&delta, &elapsed)| delta.map(|delta| delta as f64 / elapsed))
+            .fold(0.0, f64::max),
+    };
+    deltas
+        .iter()
+        .zip(&elapsed)
+        .map(|(&delta, &elapsed)| match delta? {
+            delta if delta <= 0 => Some(0.0),
+            _ if elapsed <= 0.0 || delta_per_ms <= 0.0 => None,
+            delta => Some((delta as f64 / delta_per_ms / elapsed).min(1.0)),
+        })
+        .collect()
+}
+
+/// Which of a profile's samples to transpose, and how to weight them. The default keeps every sample.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SampleFilter {
+    /// Only keep samples taken in libraries of these builds, and leave out the ones taken in other builds of the same libraries.
+    /// Frames in any other library (such as a system library) lose their native symbol, so that they aren't counted themselves, but the calls that lead to them from the builds' own functions still are.
+    pub build: BuildFilter,
+    /// Only keep samples of these threads.
+    pub threads: ThreadFilter,
+    /// Only keep samples taken within every one of these ranges.
+    pub ranges: Vec<TimeRange>,
+    /// Only keep samples taken while their thread was registered with the profiler, to leave out its startup and shutdown.
+    pub clip_to_thread_lifetime: bool,
+    pub weights: WeightPolicy,
+    pub cpu: CpuWeighting,
+}
+
+/// The number of samples that a filter left out, by the reason that they were left out.
+/// Like the samples that are kept, discarded samples are counted by their weight, and samples without a stack aren't counted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DiscardedSamples {
+    /// Samples taken in other builds of the filter's libraries.
+    pub other_builds: u64,
+    /// Samples of threads that the filter doesn't select.
+    pub other_threads: u64,
+    /// Samples taken outside the filter's time ranges, or the lifetime of their thread.
+    pub out_of_range: u64,
+    /// Samples taken while the profiler was paused, which are always left out.
+    pub paused: u64,
+    /// Samples of threads whose weights aren't sample counts, and that the weight policy doesn't convert.
+    pub unsupported_weights: u64,
+    /// Samples of threads that were (almost) idle, according to the CPU weighting, before they were scaled.
+    pub idle: u64,
+}
+
+impl DiscardedSamples {
+    pub fn total(&self) -> u64 {
+        self.other_builds
+            + self.other_threads
+            + self.out_of_range
+            + self.paused
+            + self.unsupported_weights
+            + self.idle
+    }
+
+    pub fn add(&mut self, other: &DiscardedSamples) {
+        self.other_builds += other.other_builds;
+        self.other_threads += other.other_threads;
+        self.out_of_range += other.out_of_range;
+        self.paused += other.paused;
+        self.unsupported_weights += other.unsupported_weights;
+        self.idle += other.idle;
+    }
+}
+
+/// The number of samples that a single sample stands for. Samples without a weight count once, and negative weights count as none.
+/// Traced durations are rounded to the nearest whole number of sampling intervals.
+fn sample_weight(
+    weight: Option<Weight>,
+    weight_type: WeightType,
+    profile: &fx_processed_profile::Profile,
+) -> u64 {
+    let weight = weight.map_or(1.0, Weight::as_f64).max(0.0);
+    match weight_type {
+        WeightType::Samples => weight.round() as u64,
+        WeightType::TracingMs if profile.meta.interval > 0.0 => {
+            (weight / profile.meta.interval).round() as u64
+        }
+        WeightType::TracingMs | WeightType::Bytes => 1,
+    }
+}
+
+/// The total weight of a thread's samples that have a stack, which is what discarding a whole thread discards.
+fn stacked_weight(samples: &SamplesTable, profile: &fx_processed_profile::Profile) -> u64 {
+    samples
+        .try_iter()
+        .map_while(Result::ok)
+        .filter(|s| s.stack.is_some())
+        .map(|s| sample_weight(s.weight, samples.weightType, profile))
+        .sum()
+}
+
+/// Perform a "flattening" operation
 pub fn transpose_samples<'a>(
     profile: &'a fx_processed_profile::Profile,
 ) -> Vec<TransposedSample<'a>> {
+    transpose_filtered_samples(profile, &SampleFilter::default()).0
+}
+
+/// Transpose the samples of a profile that pass a filter, and count the ones that don't.
+pub fn transpose_filtered_samples<'a>(
+    profile: &'a fx_processed_profile::Profile,
+    filter: &SampleFilter,
+) -> (Vec<TransposedSample<'a>>, DiscardedSamples) {
+    let mut discarded = DiscardedSamples::default();
+    let kept_libs: Vec<bool> = profile
+        .libs
+        .iter()
+        .map(|lib| filter.build.matches(lib))
+        .collect();
+    let other_builds = filter.build.other_builds(&profile.libs);
+    let ranges: Vec<_> = filter
+        .ranges
+        .iter()
+        .map(|range| range.resolve(profile))
+        .collect();
     let mut acc = vec![];
     // Start going through the profile, threads first:
     profile.threads.iter().for_each(|thread| {
+        if !filter.threads.matches(thread) {
+            discarded.other_threads += stacked_weight(&thread.samples, profile);
+            return;
+        }
         let stack_table: &StackTable = &thread.stackTable;
         let frame_table: &FrameTable = &thread.frameTable;
         let symbol_table: &NativeSymbolTable = &thread.nativeSymbols;
@@ -42,31 +326,114 @@ pub fn transpose_samples<'a>(
         let thread_tables = ThreadTables {
             stack_table: &thread.stackTable,
             frame_table: &thread.frameTable,
+            func_table: &thread.funcTable,
             string_table: &thread.stringTable,
             symbol_table: &thread.nativeSymbols,
         };
 
+        let in_libs = |libs: &[bool], symbol: i64| {
+            symbol_table
+                .libIndex
+                .get(symbol as usize)
+                .and_then(|&lib| libs.get(lib as usize))
+                .copied()
+                .unwrap_or(false)
+        };
+        let in_kept_lib = |symbol: i64| in_libs(&kept_libs, symbol);
+
         // Within a thread, walk samples
         let sample_table: &SamplesTable = &thread.samples;
+        let weight_type = sample_table.weightType;
+        if weight_type != WeightType::Samples && filter.weights != WeightPolicy::Convert {
+            discarded.unsupported_weights += stacked_weight(sample_table, profile);
+            return;
+        }
+        let lifetime = time_range::thread_lifetime(thread);
+        let in_range = |time: f64| {
+            ranges.iter().all(|range| range.contains(time))
+                && (!filter.clip_to_thread_lifetime || lifetime.contains(time))
+        };
+        let paused_ranges: Vec<&PausedRange> = thread
+            .pausedRanges
+            .iter()
+            .chain(profile.meta.pausedRanges.iter().flatten())
+            .collect();
+        let cpu_fractions = match filter.cpu {
+            CpuWeighting::WallClock => vec![],
+            _ => cpu_fractions(sample_table, &profile.meta),
+        };
+        let mut carry = 0.0;
 
-        for s in sample_table.iter() {
+        // Stop at the first sample that is missing from the table, and skip samples whose stack, frame or native symbol is missing from theirs.
+        for (ix, s) in sample_table.try_iter().map_while(Result::ok).enumerate() {
+            let weight = sample_weight(s.weight, weight_type, profile);
+            // Samples without a stack are neither kept nor discarded, and their CPU usage isn't carried on to the next sample.
+            if weight == 0 || s.stack.is_none() {
+                continue;
+            }
+            if !in_range(s.time) {
+                discarded.out_of_range += weight;
+                continue;
+            }
+            if paused_ranges.iter().any(|range| range.contains(s.time)) {
+                discarded.paused += weight;
+                continue;
+            }
+            let cpu_fraction = cpu_fractions.get(ix).copied().flatten();
+            let weight = match filter.cpu.apply(weight, cpu_fraction, &mut carry) {
+                // Only part of a sample, which has been carried on to the next one.
+                Some(0) => continue,
+                Some(weight) => weight,
+                None => {
+                    discarded.idle += weight;
+                    continue;
+                }
+            };
             match s.stack {
                 Some(i) => {
-                    let stack_table_entry: IndexIntoFrameTable = stack_table.frame[i as usize];
+                    let stack_table_entry: IndexIntoFrameTable =
+                        match stack_table.try_lookup(i as usize) {
+                            Some(entry) => entry.frame,
+                            None => continue,
+                        };
+
+                    let frame_table_entry = match frame_table.try_lookup(stack_table_entry as usize)
+                    {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+
+                    if frame_table_entry
+                        .nativeSymbol
+                        .is_some_and(|ix| in_libs(&other_builds, ix))
+                    {
+                        discarded.other_builds += weight;
+                        continue;
+                    }
 
-                    let frame_table_entry = frame_table.lookup(stack_table_entry as usize);
                     let string_table_index = frame_table_entry.implementation;
+                    let frames: Vec<FrameTableEntry> = stack_table
+                        .walk(i)
+                        .map_while(|(_, entry)| frame_table.try_lookup(entry.frame as usize))
+                        .map(|mut frame| {
+                            frame.nativeSymbol = frame.nativeSymbol.filter(|&ix| in_kept_lib(ix));
+                            frame
+                        })
+                        .collect();
 
                     frame_table_entry
                         .nativeSymbol
-                        .map(|ix| symbol_table.lookup(ix as usize))
+                        .and_then(|ix| symbol_table.try_lookup(ix as usize))
                         .map(|nste| {
                             let transposed_sample = TransposedSample {
                                 stack_frame: i,
+                                frame_address: frame_table_entry.address,
                                 symbol_table_entry: nste,
                                 sample_time: s.time,
-                                string_table_index: string_table_index,
-                                thread_tables: thread_tables,
+                                weight,
+                                string_table_index,
+                                frames,
+                                thread_tables,
                             };
                             acc.push(transposed_sample);
                             // Try to look up the name:
@@ -85,5 +452,5 @@ pub fn transpose_samples<'a>(
         }
         // let time_stack_pairs = table.
     });
-    acc
+    (acc, discarded)
 }
@@ -1,8 +1,37 @@
 use assert_json_diff::{assert_json_eq, assert_json_include};
-use debugid::DebugId;
+use debugid::{CodeId, DebugId};
 use serde_json::json;
 
 extern crate fx_processed_to_clang as fptc;
+use fptc::breakpad::BreakpadSymbols;
+use fptc::error::Error;
+use fptc::fx_import::{import_profile, read_profile};
+use fptc::fx_processed_profile::table_address::TableAddress;
+use fptc::fx_processed_profile::validation::{validate_profile, TableDiagnostic};
+use fptc::fx_processed_profile::{
+    Lib, PausedRange, PausedReason, ProcessType, Profile, ProfileMeta, SamplesTable,
+    ThreadCPUDeltaUnit, Weight, WeightType,
+};
+use fptc::libraries::{self, BuildFilter, LibraryAllowlist};
+use fptc::merge::{self, MergeInput};
+use fptc::profile_table_iterator::{MissingRow, TableLookup};
+use fptc::prologue::{
+    classify_offset, detect_prologue_length, CodeArch, FramePosition, PrologueClassifier,
+};
+use fptc::sample_profile::autofdo;
+use fptc::sample_profile::extbinary::{self, md5_name, ProfileSummary, SummaryEntry};
+use fptc::sample_profile::{is_assembler_name, FunctionSamples, LineLocation, SampleProfile};
+use fptc::symbol_server::{breakpad_symbol_file_name, SymbolServer};
+use fptc::symbol_source::{SourceFrame, SymbolSource};
+use fptc::symbolication::{symbolicate_profile, unresolved_libs};
+use fptc::threads::{ThreadFilter, ThreadSelector};
+use fptc::time_range::{TimeOrigin, TimeRange};
+use fptc::transposed::{CpuWeighting, SampleFilter, WeightPolicy};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wholesym::{SymbolManager, SymbolManagerConfig};
 // Tests "borrowed" from fxprof-processed-profile in samply
 
 fn serialise_deserialise_and_compare(original: serde_json::Value) {
@@ -15,10 +44,8 @@ fn serialise_deserialise_and_compare(original: serde_json::Value) {
     assert_json_eq!(original, re_parsed_profile);
 }
 
-#[test]
-fn profile_without_js() {
-    // eprintln!("{}", serde_json::to_string_pretty(&profile).unwrap());
-    serialise_deserialise_and_compare(json!(
+fn profile_without_js_json() -> serde_json::Value {
+    json!(
       {
         "meta": {
           "categories": [
@@ -721,7 +748,13 @@ fn profile_without_js() {
         //   }
         // ]
       }
-    ));
+    )
+}
+
+#[test]
+fn profile_without_js() {
+    // eprintln!("{}", serde_json::to_string_pretty(&profile).unwrap());
+    serialise_deserialise_and_compare(profile_without_js_json());
 }
 
 #[test]
@@ -933,3 +966,2039 @@ fn profile_with_js() {
       }
     ));
 }
+
+#[test]
+fn profile_without_js_llvm_text() {
+    let profile: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(profile_without_js_json()).expect("Error parsing json");
+    let samples = fptc::transposed::transpose_samples(&profile);
+    let mut classifier = PrologueClassifier::without_binaries(&profile.libs);
+    let mut sample_profile = SampleProfile::new();
+    sample_profile.add_transposed_samples(&samples, &mut classifier);
+
+    let mut written = vec![];
+    fptc::sample_profile::llvm_text::write_profile(&mut written, &sample_profile)
+        .expect("Error writing profile");
+    assert_eq!(
+        String::from_utf8(written).expect("Profile is not valid utf-8"),
+        include_str!("golden/profile_without_js.llvm.txt")
+    );
+}
+
+#[test]
+fn x86_64_prologue_detection() {
+    // endbr64; push rbp; mov rbp, rsp; push rbx; sub rsp, 0x18; mov eax, 1
+    let code = [
+        0xf3, 0x0f, 0x1e, 0xfa, 0x55, 0x48, 0x89, 0xe5, 0x53, 0x48, 0x83, 0xec, 0x18, 0xb8, 0x01,
+        0x00, 0x00, 0x00,
+    ];
+    assert_eq!(detect_prologue_length(CodeArch::X86_64, &code), 13);
+    // A leaf function without a frame: mov eax, 1; ret
+    assert_eq!(
+        detect_prologue_length(CodeArch::X86_64, &[0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3]),
+        0
+    );
+}
+
+#[test]
+fn aarch64_prologue_detection() {
+    // stp x29, x30, [sp, #-16]!; mov x29, sp; mov w0, #1
+    let code = [
+        0xfd, 0x7b, 0xbf, 0xa9, 0xfd, 0x03, 0x00, 0x91, 0x20, 0x00, 0x80, 0x52,
+    ];
+    assert_eq!(detect_prologue_length(CodeArch::Aarch64, &code), 8);
+}
+
+#[test]
+fn prologue_classification() {
+    assert_eq!(
+        classify_offset(0x1000, 0x1000, Some(0x40), 4),
+        FramePosition::Prologue
+    );
+    assert_eq!(
+        classify_offset(0x1003, 0x1000, Some(0x40), 4),
+        FramePosition::Prologue
+    );
+    assert_eq!(
+        classify_offset(0x1004, 0x1000, Some(0x40), 4),
+        FramePosition::Body
+    );
+    // Addresses outside of the symbol are never in its prologue.
+    assert_eq!(
+        classify_offset(0x0fff, 0x1000, Some(0x40), 4),
+        FramePosition::Body
+    );
+    assert_eq!(
+        classify_offset(0x1040, 0x1000, Some(0x2), 4),
+        FramePosition::Body
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn prologue_binaries_must_be_the_profiled_build() {
+    use object::Object;
+
+    // The test binary stands in for a library binary on this machine.
+    let path = std::env::current_exe().expect("Could not find the test binary");
+    let data = std::fs::read(&path).expect("Could not read the test binary");
+    let file = object::File::parse(&*data).expect("Could not parse the test binary");
+    let build_id = file
+        .build_id()
+        .unwrap()
+        .expect("The test binary has no build ID");
+    let debug_id = DebugId::from_guid_age(&build_id[..16], 0).unwrap();
+
+    let mut profiled = lib("test", "test", &debug_id.breakpad().to_string(), None);
+    profiled.path = path.to_string_lossy().into_owned();
+    // Linux libraries can also be matched by their code ID, which is their build ID.
+    let mut by_code_id = profiled.clone();
+    by_code_id.breakpadId = "000000000000000000000000000000000".to_string();
+    by_code_id.codeId = Some(build_id.iter().map(|b| format!("{:02X}", b)).collect());
+    let mut other_build = profiled.clone();
+    other_build.breakpadId = "000000000000000000000000000000000".to_string();
+    let missing = lib("missing", "missing", &debug_id.breakpad().to_string(), None);
+    let libs = vec![profiled, by_code_id, other_build, missing];
+
+    let mut classifier = PrologueClassifier::with_binaries(&libs);
+    assert!(classifier.uses_binary(0));
+    assert!(classifier.uses_binary(1));
+    assert!(!classifier.uses_binary(2));
+    assert!(!classifier.uses_binary(3));
+    let mut classifier = PrologueClassifier::without_binaries(&libs);
+    assert!(!classifier.uses_binary(0));
+}
+
+/// Serve a single file over HTTP on a local port, as a stand-in for a symbol server.
+/// Returns the base url of the server, and a count of the requests that it has received.
+async fn serve_symbol_file(path: &'static str, body: &'static str) -> (String, Arc<AtomicUsize>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind local server");
+    let base_url = format!("http://{}/", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut buffer = [0; 4096];
+            let n = stream.read(&mut buffer).await.unwrap();
+            let request = String::from_utf8_lossy(&buffer[..n]);
+            let response = if request.starts_with(&format!("GET {} ", path)) {
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (base_url, requests)
+}
+
+#[test]
+fn breakpad_symbol_file_names() {
+    assert_eq!(breakpad_symbol_file_name("XUL"), "XUL.sym");
+    assert_eq!(breakpad_symbol_file_name("libxul.so"), "libxul.so.sym");
+    assert_eq!(breakpad_symbol_file_name("xul.pdb"), "xul.sym");
+}
+
+#[tokio::test]
+async fn symbol_server_fetches_and_caches() {
+    let (base_url, requests) = serve_symbol_file(
+        "/XUL/5B2AE053F0313841AF23AA605E66A6470/XUL.sym",
+        "MODULE mac arm64 5B2AE053F0313841AF23AA605E66A6470 XUL\n",
+    )
+    .await;
+    let cache_dir = tempfile::tempdir().expect("Could not create cache directory");
+    let server = SymbolServer::new(&base_url, cache_dir.path()).expect("Invalid server url");
+
+    let path = server
+        .fetch_breakpad_symbols("XUL", "5B2AE053F0313841AF23AA605E66A6470")
+        .await
+        .expect("Could not fetch symbols");
+    assert_eq!(
+        path,
+        cache_dir
+            .path()
+            .join("XUL/5B2AE053F0313841AF23AA605E66A6470/XUL.sym")
+    );
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "MODULE mac arm64 5B2AE053F0313841AF23AA605E66A6470 XUL\n"
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // A second fetch is served from the cache.
+    server
+        .fetch_breakpad_symbols("XUL", "5B2AE053F0313841AF23AA605E66A6470")
+        .await
+        .expect("Could not fetch cached symbols");
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Missing files are not cached.
+    match server
+        .fetch_breakpad_symbols("XUL", "000000000000000000000000000000000")
+        .await
+    {
+        Err(Error::SymbolFetch { url, status }) => {
+            assert_eq!(status, 404);
+            assert!(url
+                .as_str()
+                .ends_with("/XUL/000000000000000000000000000000000/XUL.sym"));
+        }
+        other => panic!("Expected a symbol fetch error, got {:?}", other),
+    }
+    assert!(!server
+        .cache_path("XUL", "000000000000000000000000000000000", "XUL.sym")
+        .unwrap()
+        .exists());
+}
+
+#[tokio::test]
+async fn symbol_server_rejects_hostile_locations() {
+    let (base_url, requests) = serve_symbol_file(
+        "/XUL/5B2AE053F0313841AF23AA605E66A6470/XUL.sym",
+        "MODULE mac arm64 5B2AE053F0313841AF23AA605E66A6470 XUL\n",
+    )
+    .await;
+    let dir = tempfile::tempdir().expect("Could not create cache directory");
+    let cache_dir = dir.path().join("cache");
+    let server = SymbolServer::new(&base_url, &cache_dir).expect("Invalid server url");
+
+    let escape = dir.path().join("escape").to_string_lossy().into_owned();
+    let hostile = [
+        ("../escape", "5B2AE053F0313841AF23AA605E66A6470"),
+        ("../../escape", "5B2AE053F0313841AF23AA605E66A6470"),
+        (escape.as_str(), "5B2AE053F0313841AF23AA605E66A6470"),
+        ("//evil.example/XUL", "5B2AE053F0313841AF23AA605E66A6470"),
+        ("..\\escape", "5B2AE053F0313841AF23AA605E66A6470"),
+        ("XUL\0", "5B2AE053F0313841AF23AA605E66A6470"),
+        ("", "5B2AE053F0313841AF23AA605E66A6470"),
+        ("XUL", "../../escape"),
+        ("XUL", "5b2ae053f0313841af23aa605e66a6470"),
+        ("XUL", "5B2AE053F0313841AF23AA605E66A647"),
+    ];
+    for (debug_name, breakpad_id) in hostile {
+        assert!(
+            matches!(
+                server.fetch_breakpad_symbols(debug_name, breakpad_id).await,
+                Err(Error::InvalidSymbolFileLocation { .. })
+            ),
+            "{:?} {:?} should be rejected",
+            debug_name,
+            breakpad_id
+        );
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+    assert!(!cache_dir.exists());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    // Names that are valid, but aren't url-safe, stay within a single path segment.
+    assert_eq!(
+        server
+            .url("a b?#%", "5B2AE053F0313841AF23AA605E66A6470", "a b?#%.sym")
+            .unwrap()
+            .as_str(),
+        format!(
+            "{}a%20b%3F%23%25/5B2AE053F0313841AF23AA605E66A6470/a%20b%3F%23%25.sym",
+            base_url
+        )
+    );
+}
+
+const BREAKPAD_SYMBOLS: &str = "MODULE Linux x86_64 1629FCF0BE5C8860C0E1ADF03B0048FB0 libxul.so
+INFO CODE_ID F0FC29165CBE6088C0E1ADF03B0048FBECBC003A
+FILE 0 /builds/worker/checkouts/gecko/dom/base/nsINode.cpp
+FILE 1 /builds/worker/checkouts/gecko/xpcom/string/nsTString.h
+INLINE_ORIGIN 0 nsTString<char16_t>::Length() const
+INLINE_ORIGIN 1 nsTString<char16_t>::IsEmpty() const
+FUNC 1000 40 0 nsINode::GetTextContent(nsAString&)
+INLINE 0 120 0 1 1010 10
+INLINE 1 30 1 0 1014 8
+1000 10 110 0
+1010 4 120 0
+1014 8 12 1
+101c 24 130 0
+FUNC m 2000 10 0 nsINode::Release()
+2000 10 200 0
+PUBLIC 3000 0 moz_xmalloc
+PUBLIC 3100 0 moz_free
+STACK CFI INIT 1000 40 .cfa: $rsp 8 + .ra: .cfa -8 + ^
+";
+
+#[test]
+fn breakpad_symbols_parse() {
+    let symbols = BreakpadSymbols::parse(BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let module = symbols.module.as_ref().unwrap();
+    assert_eq!(module.os, "Linux");
+    assert_eq!(module.id, "1629FCF0BE5C8860C0E1ADF03B0048FB0");
+    assert_eq!(module.name, "libxul.so");
+    assert_eq!(symbols.functions.len(), 2);
+    assert_eq!(symbols.functions[0].lines.len(), 4);
+    assert_eq!(symbols.functions[0].inlines.len(), 2);
+    assert_eq!(symbols.publics.len(), 2);
+    assert_eq!(symbols.symbol_count(), 4);
+
+    match BreakpadSymbols::parse("MODULE mac arm64 0 XUL\nFUNC zzz 10 0 broken".as_bytes()) {
+        Err(Error::InvalidSymbolFile { path, line, record }) => {
+            assert_eq!(path, None);
+            assert_eq!(line, 2);
+            assert_eq!(record, "FUNC zzz 10 0 broken");
+        }
+        other => panic!("Expected an invalid symbol file error, got {:?}", other),
+    }
+}
+
+#[test]
+fn breakpad_symbols_lookup() {
+    let symbols = BreakpadSymbols::parse(BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let frame = |function: &str, file: Option<&str>, line: Option<u32>| SourceFrame {
+        function: function.to_string(),
+        file: file.map(|f| f.to_string()),
+        line,
+    };
+    let node_cpp = "/builds/worker/checkouts/gecko/dom/base/nsINode.cpp";
+    let string_h = "/builds/worker/checkouts/gecko/xpcom/string/nsTString.h";
+
+    // An address without any inlining.
+    let lookup = symbols.lookup(0x1004).unwrap();
+    assert_eq!(lookup.name, "nsINode::GetTextContent(nsAString&)");
+    assert_eq!(lookup.address, 0x1000);
+    assert_eq!(lookup.size, Some(0x40));
+    assert_eq!(
+        lookup.frames,
+        vec![frame(
+            "nsINode::GetTextContent(nsAString&)",
+            Some(node_cpp),
+            Some(110)
+        )]
+    );
+
+    // An address with two levels of inlining, innermost first.
+    let lookup = symbols.lookup(0x1016).unwrap();
+    assert_eq!(
+        lookup.frames,
+        vec![
+            frame(
+                "nsTString<char16_t>::Length() const",
+                Some(string_h),
+                Some(12)
+            ),
+            frame(
+                "nsTString<char16_t>::IsEmpty() const",
+                Some(string_h),
+                Some(30)
+            ),
+            frame(
+                "nsINode::GetTextContent(nsAString&)",
+                Some(node_cpp),
+                Some(120)
+            ),
+        ]
+    );
+
+    // Public symbols extend up to the next symbol.
+    let lookup = symbols.lookup(0x3050).unwrap();
+    assert_eq!(lookup.name, "moz_xmalloc");
+    assert_eq!(lookup.size, None);
+    assert_eq!(symbols.lookup(0x3100).unwrap().name, "moz_free");
+
+    // Addresses before the first symbol, or in a gap after a function, are not found.
+    assert!(symbols.lookup(0x500).is_none());
+    assert!(symbols.lookup(0x2010).is_none());
+}
+
+fn lib(name: &str, debug_name: &str, breakpad_id: &str, arch: Option<&str>) -> Lib {
+    Lib {
+        arch: arch.map(|a| a.to_string()),
+        name: name.to_string(),
+        path: format!("/path/to/{}", name),
+        debugName: debug_name.to_string(),
+        debugPath: format!("/path/to/{}", debug_name),
+        breakpadId: breakpad_id.to_string(),
+        codeId: None,
+    }
+}
+
+#[test]
+fn library_allowlist() {
+    let allowlist = LibraryAllowlist::mozilla();
+    let xul_mac = lib("XUL", "XUL", "5B2AE053F0313841AF23AA605E66A6470", None);
+    let xul_linux = lib(
+        "libxul.so",
+        "libxul.so",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB0",
+        None,
+    );
+    let xul_windows = lib(
+        "xul.dll",
+        "xul.pdb",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB1",
+        None,
+    );
+    let libc = lib(
+        "libc.so.6",
+        "libc.so.6",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB0",
+        None,
+    );
+    assert!(allowlist.contains(&xul_mac));
+    assert!(allowlist.contains(&xul_linux));
+    assert!(allowlist.contains(&xul_windows));
+    assert!(!allowlist.contains(&libc));
+
+    // Windows libraries can also be matched by their debug name.
+    let allowlist = LibraryAllowlist::new(["xul.pdb", "libc.so.6"]);
+    assert!(allowlist.contains(&xul_windows));
+    assert!(allowlist.contains(&libc));
+    assert!(!allowlist.contains(&xul_mac));
+}
+
+#[test]
+fn library_disambiguation() {
+    let xul = lib(
+        "XUL",
+        "XUL",
+        "5B2AE053F0313841AF23AA605E66A6470",
+        Some("arm64"),
+    );
+    assert_eq!(
+        libraries::debug_id(&xul),
+        DebugId::from_breakpad("5B2AE053F0313841AF23AA605E66A6470").ok()
+    );
+    assert!(matches!(
+        libraries::disambiguator(&xul),
+        Some(wholesym::MultiArchDisambiguator::DebugId(_))
+    ));
+
+    // Without a valid debug ID, we fall back to the architecture.
+    let xul = lib("XUL", "XUL", "not an id", Some("x86_64h"));
+    assert!(matches!(
+        libraries::disambiguator(&xul),
+        Some(wholesym::MultiArchDisambiguator::Arch(arch)) if arch == "x86_64h"
+    ));
+}
+
+const LIBC_BREAKPAD_SYMBOLS: &str =
+    "MODULE Linux x86_64 1629FCF0BE5C8860C0E1ADF03B0048FB0 libc.so.6
+FILE 0 /build/glibc/libio/fileops.c
+FILE 1 /build/glibc/csu/libc-start.c
+INLINE_ORIGIN 0 new_do_write
+FUNC 19f0a1 b4 0 _IO_new_file_write
+INLINE 0 1185 0 0 19f0e1 20
+19f0a1 40 1180 0
+19f0e1 20 442 0
+19f101 54 1190 0
+FUNC 2a07c 14 0 __libc_start_call_main
+2a07c 14 58 1
+FUNC a49b2 2c 0 __poll
+";
+
+#[test]
+fn symbolicate_profile_without_js() {
+    let profile: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(profile_without_js_json()).expect("Error parsing json");
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let sources = vec![None, Some(SymbolSource::Breakpad(libc))];
+    // There are no symbols for dump_syms, so the profile isn't marked as symbolicated, and can be symbolicated again later.
+    assert_eq!(unresolved_libs(&profile, &sources), vec![0]);
+    let profile = symbolicate_profile(profile, &sources);
+    assert_eq!(profile.meta.symbolicated, Some(false));
+    let thread = &profile.threads[0];
+    let string = |ix: i64| thread.stringTable[ix as usize].as_str();
+
+    // Native symbols are renamed, and keep their sizes.
+    let names: Vec<&str> = thread
+        .nativeSymbols
+        .name
+        .iter()
+        .map(|&n| string(n))
+        .collect();
+    assert_eq!(
+        names,
+        vec!["_IO_new_file_write", "__libc_start_call_main", "__poll"]
+    );
+    assert_eq!(thread.nativeSymbols.functionSize[0], Some(180));
+
+    // Frames in libc get a function with the resolved name and file, and a line number.
+    let func = thread.frameTable.func[6].unwrap() as usize;
+    assert_eq!(string(thread.funcTable.name[func]), "_IO_new_file_write");
+    assert_eq!(
+        thread.funcTable.fileName[func].map(string),
+        Some("/build/glibc/libio/fileops.c")
+    );
+    assert_eq!(thread.frameTable.line[6], Some(1185));
+    assert_eq!(thread.frameTable.nativeSymbol[6], Some(0));
+    let func = thread.frameTable.func[7].unwrap() as usize;
+    assert_eq!(
+        thread.funcTable.fileName[func].map(string),
+        Some("/build/glibc/csu/libc-start.c")
+    );
+    assert_eq!(thread.frameTable.line[7], Some(58));
+    // Symbols without line information still resolve to a function.
+    let func = thread.frameTable.func[15].unwrap() as usize;
+    assert_eq!(string(thread.funcTable.name[func]), "__poll");
+    assert_eq!(thread.frameTable.line[15], None);
+
+    // Frames in libraries without symbols are untouched.
+    assert_eq!(thread.frameTable.func[11], Some(11));
+    assert_eq!(string(thread.funcTable.name[11]), "0x25318e");
+    assert_eq!(thread.frameTable.nativeSymbol[11], None);
+
+    // The tables stay consistent, and the profile can be written back out.
+    assert_eq!(
+        thread.funcTable.length as usize,
+        thread.funcTable.name.len()
+    );
+    assert_eq!(
+        thread.funcTable.length as usize,
+        thread.funcTable.fileName.len()
+    );
+    let reparsed: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(serde_json::to_value(&profile).expect("Error dumping json"))
+            .expect("Error parsing json");
+    assert_eq!(reparsed, profile);
+
+    // Once every library with native frames has symbols, the profile is marked as symbolicated.
+    let profile: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(profile_without_js_json()).expect("Error parsing json");
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let dump_syms = BreakpadSymbols::parse(
+        "MODULE Linux x86_64 5C0A0D51EA1980DF43F203B4525BE9BE0 dump_syms\nFUNC 25318e 4 0 main\n"
+            .as_bytes(),
+    )
+    .expect("Invalid symbols");
+    let sources = vec![
+        Some(SymbolSource::Breakpad(dump_syms)),
+        Some(SymbolSource::Breakpad(libc)),
+    ];
+    assert!(unresolved_libs(&profile, &sources).is_empty());
+    let profile = symbolicate_profile(profile, &sources);
+    assert_eq!(profile.meta.symbolicated, Some(true));
+}
+
+#[test]
+fn symbolicate_inlined_frames() {
+    let profile: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(profile_without_js_json()).expect("Error parsing json");
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let profile = symbolicate_profile(profile, &[None, Some(SymbolSource::Breakpad(libc))]);
+    let thread = &profile.threads[0];
+    let string = |ix: i64| thread.stringTable[ix as usize].as_str();
+
+    // The inlined function gets a new frame at the same address, one level deeper.
+    assert_eq!(thread.frameTable.length, 17);
+    assert_eq!(thread.frameTable.inlineDepth[6], 0);
+    assert_eq!(thread.frameTable.inlineDepth[16], 1);
+    assert_eq!(thread.frameTable.address[16], thread.frameTable.address[6]);
+    assert_eq!(
+        thread.frameTable.nativeSymbol[16],
+        thread.frameTable.nativeSymbol[6]
+    );
+    let func = thread.frameTable.func[16].unwrap() as usize;
+    assert_eq!(string(thread.funcTable.name[func]), "new_do_write");
+    assert_eq!(thread.frameTable.line[16], Some(442));
+
+    // The sample now points to a new stack for the inlined frame, which is a child of the original stack.
+    assert_eq!(thread.stackTable.length, 17);
+    assert_eq!(thread.stackTable.frame[16], 16);
+    assert_eq!(thread.stackTable.prefix[16], Some(6));
+    assert_eq!(thread.samples.stack[1], Some(16));
+    // Other stacks are unchanged.
+    assert_eq!(thread.samples.stack[3], Some(15));
+    assert_eq!(thread.stackTable.prefix[7], Some(1));
+
+    let samples = fptc::transposed::transpose_samples(&profile);
+    let mut classifier = PrologueClassifier::without_binaries(&profile.libs);
+    let mut sample_profile = SampleProfile::new();
+    sample_profile.add_transposed_samples(&samples, &mut classifier);
+    let mut written = vec![];
+    fptc::sample_profile::llvm_text::write_profile(&mut written, &sample_profile)
+        .expect("Error writing profile");
+    assert_eq!(
+        String::from_utf8(written).expect("Profile is not valid utf-8"),
+        include_str!("golden/profile_without_js_symbolicated.llvm.txt")
+    );
+
+    // Symbolicating again, as we do for profiles that were only partly symbolicated, doesn't expand the inlined frames a second time.
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let copy: fptc::fx_processed_profile::Profile =
+        serde_json::from_value(serde_json::to_value(&profile).expect("Error dumping json"))
+            .expect("Error parsing json");
+    let again = symbolicate_profile(copy, &[None, Some(SymbolSource::Breakpad(libc))]);
+    assert_eq!(again, profile);
+}
+
+#[test]
+fn profile_meta_round_trip() {
+    let original = profile_without_js_json()["meta"].clone();
+    let meta: ProfileMeta = serde_json::from_value(original.clone()).expect("Error parsing meta");
+    assert_eq!(meta.interval, 1.0);
+    assert_eq!(meta.startTime, 1636162232627.0);
+    assert_eq!(meta.preprocessedProfileVersion, 46);
+    assert_eq!(meta.product, "test");
+    assert_eq!(meta.symbolicated, Some(false));
+    assert_eq!(meta.platform, None);
+    assert_eq!(
+        meta.sampleUnits.as_ref().map(|units| units.threadCPUDelta),
+        Some(ThreadCPUDeltaUnit::Microseconds)
+    );
+    let categories = meta.categories.as_ref().unwrap();
+    assert_eq!(categories.len(), 2);
+    assert_eq!(categories[1].name, "Regular");
+    // Fields that aren't typed are kept as they are.
+    assert!(meta.other.contains_key("markerSchema"));
+
+    let serialized = serde_json::to_value(&meta).expect("Error dumping meta");
+    assert_json_eq!(original, serialized);
+}
+
+#[test]
+fn profile_meta_platform_fields() {
+    let meta: ProfileMeta = serde_json::from_value(json!({
+        "interval": 0.5,
+        "startTime": 1684152000000.0,
+        "processType": 0,
+        "product": "Firefox",
+        "stackwalk": 1,
+        "version": 27,
+        "preprocessedProfileVersion": 47,
+        "abi": "x86_64-gcc3",
+        "platform": "X11",
+        "oscpu": "Linux x86_64",
+        "toolkit": "gtk",
+        "appBuildID": "20230515094736",
+        "symbolicated": true,
+        "sampleUnits": {
+            "time": "ms",
+            "eventDelay": "ms",
+            "threadCPUDelta": "ns"
+        }
+    }))
+    .expect("Error parsing meta");
+    assert_eq!(meta.abi.as_deref(), Some("x86_64-gcc3"));
+    assert_eq!(meta.platform.as_deref(), Some("X11"));
+    assert_eq!(meta.oscpu.as_deref(), Some("Linux x86_64"));
+    assert_eq!(meta.appBuildID.as_deref(), Some("20230515094736"));
+    assert_eq!(
+        meta.sampleUnits.unwrap().threadCPUDelta,
+        ThreadCPUDeltaUnit::Nanoseconds
+    );
+}
+
+#[test]
+fn import_plain_and_gzipped_profiles() {
+    let original = profile_without_js_json();
+    let raw_json = serde_json::to_vec(&original).unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&raw_json).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let expected: Profile = serde_json::from_value(original).unwrap();
+    let plain = read_profile(raw_json.as_slice()).expect("Error reading plain profile");
+    let gzipped = read_profile(compressed.as_slice()).expect("Error reading gzipped profile");
+    assert_eq!(plain, expected);
+    assert_eq!(gzipped, expected);
+
+    // The format is detected from the contents, not the file name.
+    let dir = tempfile::tempdir().expect("Could not create profile directory");
+    let path = dir.path().join("profile.json");
+    std::fs::write(&path, &compressed).unwrap();
+    assert_eq!(
+        import_profile(&path).expect("Error importing profile"),
+        expected
+    );
+
+    assert!(matches!(
+        read_profile(&compressed[..compressed.len() / 2]),
+        Err(Error::Json { path: None, .. })
+    ));
+
+    // Errors from files say which file they came from.
+    let missing = dir.path().join("missing.json.gz");
+    match import_profile(&missing) {
+        Err(Error::Io { path, .. }) => assert_eq!(path, Some(missing)),
+        other => panic!("Expected an IO error, got {:?}", other),
+    }
+    let invalid = dir.path().join("invalid.json");
+    std::fs::write(&invalid, "{\"meta\": ").unwrap();
+    match import_profile(&invalid) {
+        Err(e @ Error::Json { .. }) => {
+            assert!(e.to_string().contains("invalid.json"));
+        }
+        other => panic!("Expected a JSON error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn find_symbol_map_reports_failures() {
+    let (base_url, requests) = serve_symbol_file(
+        "/XUL/5B2AE053F0313841AF23AA605E66A6470/XUL.sym",
+        "MODULE mac arm64 5B2AE053F0313841AF23AA605E66A6470 XUL\n",
+    )
+    .await;
+    let cache_dir = tempfile::tempdir().expect("Could not create cache directory");
+    let server = SymbolServer::new(&base_url, cache_dir.path()).expect("Invalid server url");
+    let symbol_manager = SymbolManager::with_config(SymbolManagerConfig::new());
+    let allowlist = LibraryAllowlist::mozilla();
+
+    // Libraries outside of the allowlist are skipped without an error, or a request.
+    let libc = lib(
+        "libc.so.6",
+        "libc.so.6",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB0",
+        None,
+    );
+    assert!(matches!(
+        fptc::find_symbol_map(&libc, &symbol_manager, &server, &allowlist).await,
+        Ok(None)
+    ));
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    // Without local binaries, the symbol server is the last resort.
+    let xul = lib("XUL", "XUL", "5B2AE053F0313841AF23AA605E66A6470", None);
+    match fptc::find_symbol_map(&xul, &symbol_manager, &server, &allowlist).await {
+        Ok(Some(SymbolSource::Breakpad(symbols))) => {
+            assert_eq!(symbols.module.unwrap().name, "XUL")
+        }
+        _ => panic!("Expected Breakpad symbols for XUL"),
+    }
+
+    // If every source fails, we find out why each of them did.
+    let libxul = lib(
+        "libxul.so",
+        "libxul.so",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB0",
+        None,
+    );
+    match fptc::find_symbol_map(&libxul, &symbol_manager, &server, &allowlist).await {
+        Err(Error::MissingDebugFile {
+            library,
+            breakpad_id,
+            attempts,
+        }) => {
+            assert_eq!(library, "libxul.so");
+            assert_eq!(breakpad_id, "1629FCF0BE5C8860C0E1ADF03B0048FB0");
+            assert_eq!(attempts.len(), 3);
+            assert!(matches!(attempts[0], Error::Symbols(_)));
+            assert!(matches!(attempts[1], Error::Symbols(_)));
+            assert!(matches!(attempts[2], Error::SymbolFetch { .. }));
+        }
+        _ => panic!("Expected a missing debug file error for libxul.so"),
+    }
+}
+
+#[test]
+fn profile_table_validation() {
+    let profile: Profile = serde_json::from_value(profile_without_js_json()).unwrap();
+    assert_eq!(validate_profile(&profile), vec![]);
+
+    let mut broken = profile_without_js_json();
+    let thread = &mut broken["threads"][0];
+    thread["samples"]["time"].as_array_mut().unwrap().pop();
+    thread["stackTable"]["frame"][4] = json!(16);
+    // 1 -> 3 -> 2 -> 1
+    thread["stackTable"]["prefix"][1] = json!(3);
+    thread["nativeSymbols"]["libIndex"][2] = json!(2);
+
+    let profile: Profile = serde_json::from_value(broken.clone()).unwrap();
+    let diagnostic = |table, column, row, message: &str| TableDiagnostic {
+        thread: 0,
+        table,
+        column: Some(column),
+        row,
+        message: message.to_string(),
+    };
+    let expected = vec![
+        diagnostic(
+            "samples",
+            "time",
+            None,
+            "has 3 entries, but the table has a length of 4",
+        ),
+        diagnostic(
+            "stackTable",
+            "frame",
+            Some(4),
+            "16 is not a valid index into the frameTable (length 16)",
+        ),
+        diagnostic(
+            "stackTable",
+            "prefix",
+            Some(1),
+            "is part of a cycle of prefixes",
+        ),
+        diagnostic(
+            "nativeSymbols",
+            "libIndex",
+            Some(2),
+            "2 is not a valid index into the libs (length 2)",
+        ),
+    ];
+    assert_eq!(validate_profile(&profile), expected);
+    assert_eq!(
+        expected[1].to_string(),
+        "thread 0: stackTable.frame[4]: 16 is not a valid index into the frameTable (length 16)"
+    );
+
+    // Broken profiles are rejected when they are loaded, and before they are transposed.
+    let raw_json = serde_json::to_vec(&broken).unwrap();
+    match read_profile(raw_json.as_slice()) {
+        Err(Error::InconsistentTables(diagnostics)) => assert_eq!(diagnostics, expected),
+        other => panic!("Expected inconsistent tables, got {:?}", other),
+    }
+    assert!(matches!(
+        fptc::aggregate_profile(&profile),
+        Err(Error::InconsistentTables(_))
+    ));
+}
+
+#[test]
+fn truncated_tables() {
+    let mut truncated = profile_without_js_json();
+    let thread = &mut truncated["threads"][0];
+    // The last sample has no time, and the stack of the third sample has no frame.
+    thread["samples"]["time"].as_array_mut().unwrap().pop();
+    thread["stackTable"]["frame"]
+        .as_array_mut()
+        .unwrap()
+        .truncate(11);
+    let profile: Profile = serde_json::from_value(truncated).unwrap();
+    let thread = &profile.threads[0];
+
+    assert!(thread.stackTable.try_lookup(10).is_some());
+    assert_eq!(thread.stackTable.try_lookup(11), None);
+    assert_eq!(thread.stackTable.try_lookup(16), None);
+    assert_eq!(thread.samples.try_lookup(3), None);
+
+    // Iteration stops after the first missing row.
+    let samples: Vec<_> = thread.samples.try_iter().collect();
+    assert_eq!(samples.len(), 4);
+    assert!(samples[..3].iter().all(|s| s.is_ok()));
+    assert_eq!(samples[3], Err(MissingRow { row: 3, length: 4 }));
+
+    // Transposition skips what it can't look up, rather than panicking.
+    let transposed = fptc::transposed::transpose_samples(&profile);
+    let stacks: Vec<i64> = transposed.iter().map(|s| s.stack_frame).collect();
+    assert_eq!(stacks, vec![6]);
+}
+
+#[test]
+fn walk_full_stacks() {
+    let profile: Profile = serde_json::from_value(profile_without_js_json()).unwrap();
+    let stack_table = &profile.threads[0].stackTable;
+    let stacks: Vec<i64> = stack_table.walk(11).map(|(ix, _)| ix).collect();
+    assert_eq!(stacks, vec![11, 10, 9, 8, 7, 1, 0]);
+    let frames: Vec<i64> = stack_table.walk(11).map(|(_, entry)| entry.frame).collect();
+    assert_eq!(frames, vec![11, 10, 9, 8, 7, 1, 0]);
+    assert_eq!(stack_table.walk(0).count(), 1);
+    assert_eq!(stack_table.walk(16).count(), 0);
+
+    // The sample on stack 15 carries all of the frames from 15 down to the root.
+    let transposed = fptc::transposed::transpose_samples(&profile);
+    let sample = transposed.iter().find(|s| s.stack_frame == 15).unwrap();
+    let addresses: Vec<TableAddress> = sample.frames.iter().map(|f| f.address).collect();
+    assert_eq!(
+        addresses,
+        vec![
+            TableAddress::Address(0xa49c6),
+            TableAddress::Address(0x2778f4),
+            TableAddress::Address(0xb40e2),
+            TableAddress::Address(0x1571b8),
+            TableAddress::Address(0x2a07c),
+            TableAddress::Address(0xc2704),
+            TableAddress::Base,
+        ]
+    );
+    assert_eq!(sample.frames[0].address, sample.frame_address);
+    assert_eq!(sample.caller_frames(), &sample.frames[1..]);
+    assert!(sample.inlined_function_names().is_empty());
+}
+
+#[test]
+fn walk_stacks_with_cycles() {
+    let mut cyclic = profile_without_js_json();
+    cyclic["threads"][0]["stackTable"]["prefix"][1] = json!(3);
+    let profile: Profile = serde_json::from_value(cyclic).unwrap();
+    let stack_table = &profile.threads[0].stackTable;
+    // 3 -> 2 -> 1 -> 3 -> ..., cut off after one step per stack.
+    assert_eq!(stack_table.walk(3).count(), 16);
+}
+
+fn sample_profile_text(profile: &Profile) -> String {
+    let samples = fptc::transposed::transpose_samples(profile);
+    let mut classifier = PrologueClassifier::without_binaries(&profile.libs);
+    let mut sample_profile = SampleProfile::new();
+    sample_profile.add_transposed_samples(&samples, &mut classifier);
+    let mut written = vec![];
+    fptc::sample_profile::llvm_text::write_profile(&mut written, &sample_profile)
+        .expect("Error writing profile");
+    String::from_utf8(written).expect("Profile is not valid utf-8")
+}
+
+#[test]
+fn callsite_call_targets() {
+    // Make libc_symbol_2 the direct caller of libc_symbol_3 on stack 15.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["frameTable"]["nativeSymbol"][14] = json!(1);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    assert_eq!(
+        sample_profile_text(&profile),
+        "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\nlibc_symbol_2:0:0\n 0: 0 libc_symbol_3:1\n"
+    );
+}
+
+#[test]
+fn callsite_call_targets_from_inlined_functions() {
+    // Make __poll a call from new_do_write, which has been inlined into _IO_new_file_write.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["frameTable"]["nativeSymbol"][14] = json!(0);
+    json["threads"][0]["frameTable"]["address"][14] = json!(0x19f0e5);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    let profile = symbolicate_profile(profile, &[None, Some(SymbolSource::Breakpad(libc))]);
+    assert_eq!(
+        sample_profile_text(&profile),
+        "_IO_new_file_write:1:0\n 5: new_do_write:1\n  0: 1 __poll:1\n__poll:1:0\n 0: 1\n"
+    );
+}
+
+#[test]
+fn line_offsets_relative_to_function_start() {
+    let location = |line_offset| LineLocation {
+        line_offset,
+        discriminator: 0,
+    };
+    assert_eq!(
+        LineLocation::from_lines(Some(1190), Some(1180)),
+        location(10)
+    );
+    assert_eq!(
+        LineLocation::from_lines(Some(1180), Some(1180)),
+        location(0)
+    );
+    // Lines before the start of the function wrap around, as they do in LLVM.
+    assert_eq!(
+        LineLocation::from_lines(Some(1179), Some(1180)),
+        location(0xffff)
+    );
+    assert_eq!(LineLocation::from_lines(Some(1190), None), location(0));
+    assert_eq!(LineLocation::from_lines(None, Some(1180)), location(0));
+
+    // Functions start at the line of their first instruction.
+    let libc = BreakpadSymbols::parse(LIBC_BREAKPAD_SYMBOLS.as_bytes()).expect("Invalid symbols");
+    assert_eq!(libc.lookup(0x19f110).unwrap().start_line, Some(1180));
+    assert_eq!(libc.lookup(0xa49c6).unwrap().start_line, None);
+
+    // Move the sample on stack 15 into the body of _IO_new_file_write, on line 1190.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["frameTable"]["nativeSymbol"][15] = json!(0);
+    json["threads"][0]["frameTable"]["address"][15] = json!(0x19f110);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let profile = symbolicate_profile(profile, &[None, Some(SymbolSource::Breakpad(libc))]);
+    let thread = &profile.threads[0];
+    let func = thread.frameTable.func[15].unwrap() as usize;
+    assert_eq!(thread.funcTable.lineNumber[func], Some(1180));
+    assert_eq!(thread.frameTable.line[15], Some(1190));
+
+    // The inlined call on line 1185 is at offset 5, and new_do_write doesn't have a known start line, so its sample is at offset 0.
+    assert_eq!(
+        sample_profile_text(&profile),
+        "_IO_new_file_write:2:0\n 10: 1\n 5: new_do_write:1\n  0: 1\n"
+    );
+}
+
+/// A minimal reader for LLVM's extensible binary format, which decodes a profile back into our model so that it can be compared with the text format.
+struct ExtBinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ExtBinaryReader<'a> {
+    fn uleb128(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.data[self.pos];
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn u64(&mut self) -> u64 {
+        let bytes = self.data[self.pos..self.pos + 8].try_into().unwrap();
+        self.pos += 8;
+        u64::from_le_bytes(bytes)
+    }
+
+    fn string(&mut self) -> String {
+        let len = self.data[self.pos..].iter().position(|&b| b == 0).unwrap();
+        let s = String::from_utf8(self.data[self.pos..self.pos + len].to_vec()).unwrap();
+        self.pos += len + 1;
+        s
+    }
+
+    fn location(&mut self) -> LineLocation {
+        LineLocation {
+            line_offset: self.uleb128() as u32,
+            discriminator: self.uleb128() as u32,
+        }
+    }
+
+    fn function_body(&mut self, names: &[String]) -> FunctionSamples {
+        let mut function = FunctionSamples::new(&names[self.uleb128() as usize]);
+        function.total_samples = self.uleb128();
+        for _ in 0..self.uleb128() {
+            let location = self.location();
+            function.body_samples.insert(location, self.uleb128());
+            for _ in 0..self.uleb128() {
+                let callee = &names[self.uleb128() as usize];
+                let count = self.uleb128();
+                function.add_call_target_samples(location, callee, count);
+            }
+        }
+        for _ in 0..self.uleb128() {
+            let location = self.location();
+            let callee = self.function_body(names);
+            function
+                .callsite_samples
+                .entry(location)
+                .or_default()
+                .insert(callee.name.clone(), callee);
+        }
+        function
+    }
+}
+
+struct DecodedExtBinary {
+    summary: ProfileSummary,
+    name_table_flags: u64,
+    profile: SampleProfile,
+}
+
+/// Decode an extbinary profile, looking up MD5 names in `md5_names`.
+fn read_ext_binary(data: &[u8], md5_names: &[&str]) -> DecodedExtBinary {
+    let mut reader = ExtBinaryReader { data, pos: 0 };
+    assert_eq!(reader.uleb128(), 0x5350524f46343204, "Wrong magic");
+    assert_eq!(reader.uleb128(), 103, "Wrong version");
+    let sections: Vec<(u64, u64, u64, u64)> = (0..reader.u64())
+        .map(|_| (reader.u64(), reader.u64(), reader.u64(), reader.u64()))
+        .collect();
+
+    let mut decoded = DecodedExtBinary {
+        summary: ProfileSummary::default(),
+        name_table_flags: 0,
+        profile: SampleProfile::new(),
+    };
+    let mut names = vec![];
+    let mut offsets = vec![];
+    let mut function_offsets = vec![];
+    for (ty, flags, offset, size) in sections {
+        let start = offset as usize;
+        let end = start + size as usize;
+        let mut reader = ExtBinaryReader {
+            data: &data[..end],
+            pos: start,
+        };
+        match ty {
+            1 => {
+                let summary = &mut decoded.summary;
+                summary.total_count = reader.uleb128();
+                summary.max_count = reader.uleb128();
+                summary.max_function_count = reader.uleb128();
+                summary.num_counts = reader.uleb128();
+                summary.num_functions = reader.uleb128();
+                for _ in 0..reader.uleb128() {
+                    summary.detailed_summary.push(SummaryEntry {
+                        cutoff: reader.uleb128() as u32,
+                        min_count: reader.uleb128(),
+                        num_counts: reader.uleb128(),
+                    });
+                }
+            }
+            2 => {
+                decoded.name_table_flags = flags;
+                for _ in 0..reader.uleb128() {
+                    if flags & (1 << 32) != 0 {
+                        let hash = reader.u64();
+                        let name = md5_names.iter().find(|name| md5_name(name) == hash);
+                        names.push(name.expect("Unknown MD5 name").to_string());
+                    } else {
+                        names.push(reader.string());
+                    }
+                }
+            }
+            4 => {
+                for _ in 0..reader.uleb128() {
+                    offsets.push((names[reader.uleb128() as usize].clone(), reader.uleb128()));
+                }
+            }
+            0x20 => {
+                while reader.pos < end {
+                    let offset = (reader.pos - start) as u64;
+                    let head_samples = reader.uleb128();
+                    let mut function = reader.function_body(&names);
+                    function.head_samples = head_samples;
+                    function_offsets.push((function.name.clone(), offset));
+                    decoded
+                        .profile
+                        .functions
+                        .insert(function.name.clone(), function);
+                }
+                assert_eq!(reader.pos, end, "Function profiles overrun their section");
+            }
+            _ => panic!("Unexpected section type {:#x}", ty),
+        }
+    }
+    assert_eq!(offsets, function_offsets);
+    decoded
+}
+
+fn example_sample_profile() -> SampleProfile {
+    let location = |line_offset, discriminator| LineLocation {
+        line_offset,
+        discriminator,
+    };
+    let mut profile = SampleProfile::new();
+    let main = profile.function_mut("main");
+    main.add_head_samples(2);
+    main.add_body_samples(location(0, 0), 2);
+    // Large enough to need more than one byte.
+    main.add_body_samples(location(3, 1), 200);
+    main.add_call_target_samples(location(3, 1), "work", 150);
+    main.add_call_target_samples(location(4, 0), "helper.__uniq.123", 7);
+    main.add_inlined_body_samples(&[(location(5, 0), "inlined")], location(1, 0), 30);
+    main.add_inlined_call_target_samples(&[(location(5, 0), "inlined")], location(2, 0), "work", 5);
+    let work = profile.function_mut("work");
+    work.add_head_samples(155);
+    work.add_body_samples(location(0, 0), 155);
+    profile
+        .function_mut("helper.__uniq.123")
+        .add_body_samples(location(1, 0), 7);
+    profile
+}
+
+fn llvm_text(profile: &SampleProfile) -> String {
+    let mut written = vec![];
+    fptc::sample_profile::llvm_text::write_profile(&mut written, profile)
+        .expect("Error writing profile");
+    String::from_utf8(written).expect("Profile is not valid utf-8")
+}
+
+#[test]
+fn ext_binary_matches_text() {
+    let profile = example_sample_profile();
+    let mut written = vec![];
+    extbinary::write_profile(&mut written, &profile, &extbinary::Options::default())
+        .expect("Error writing profile");
+    let decoded = read_ext_binary(&written, &[]);
+
+    assert_eq!(llvm_text(&decoded.profile), llvm_text(&profile));
+    // Names with a unique suffix are flagged, so that clang knows to match them.
+    assert_eq!(decoded.name_table_flags, 1 << 34);
+
+    let summary = ProfileSummary::from_profile(&profile);
+    assert_eq!(decoded.summary, summary);
+    assert_eq!(summary.total_count, 394);
+    assert_eq!(summary.max_count, 200);
+    assert_eq!(summary.max_function_count, 155);
+    // Call targets without samples of their own still count as a location.
+    assert_eq!(summary.num_counts, 7);
+    assert_eq!(summary.num_functions, 3);
+    assert_eq!(summary.detailed_summary.len(), 16);
+    assert_eq!(
+        summary.detailed_summary[0],
+        SummaryEntry {
+            cutoff: 10000,
+            min_count: 200,
+            num_counts: 1,
+        }
+    );
+    assert_eq!(
+        summary.detailed_summary[15],
+        SummaryEntry {
+            cutoff: 999999,
+            min_count: 2,
+            num_counts: 5,
+        }
+    );
+}
+
+#[test]
+fn ext_binary_md5_names() {
+    assert_eq!(md5_name("main"), 15822663052811949562);
+
+    let profile = example_sample_profile();
+    let mut written = vec![];
+    let options = extbinary::Options { use_md5: true };
+    extbinary::write_profile(&mut written, &profile, &options).expect("Error writing profile");
+    let names = ["main", "work", "inlined", "helper.__uniq.123"];
+    let decoded = read_ext_binary(&written, &names);
+
+    assert_eq!(llvm_text(&decoded.profile), llvm_text(&profile));
+    assert_eq!(decoded.name_table_flags, 1 << 32 | 1 << 33);
+    assert!(!written.windows(4).any(|w| w == b"main"));
+}
+
+/// A minimal reader for GCC's AutoFDO format, which rebuilds the totals of each function instance in the same way that GCC does.
+struct AutofdoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    names: Vec<String>,
+}
+
+impl<'a> AutofdoReader<'a> {
+    fn unsigned(&mut self) -> u32 {
+        let bytes = self.data[self.pos..self.pos + 4].try_into().unwrap();
+        self.pos += 4;
+        u32::from_le_bytes(bytes)
+    }
+
+    fn counter(&mut self) -> u64 {
+        self.unsigned() as u64 | (self.unsigned() as u64) << 32
+    }
+
+    fn string(&mut self) -> String {
+        let length = self.unsigned() as usize;
+        let bytes = &self.data[self.pos..self.pos + length];
+        self.pos += length;
+        assert_eq!(bytes.last(), Some(&0));
+        String::from_utf8(bytes[..length - 1].to_vec()).unwrap()
+    }
+
+    fn location(&mut self) -> LineLocation {
+        let offset = self.unsigned();
+        LineLocation {
+            line_offset: offset >> 16,
+            discriminator: offset & 0xffff,
+        }
+    }
+
+    /// Read a section's tag and length, and return the position that it should end at.
+    fn section(&mut self, tag: u32) -> usize {
+        assert_eq!(self.unsigned(), tag, "Wrong section tag");
+        let length = self.unsigned() as usize;
+        self.pos + length
+    }
+
+    fn function_instance(&mut self) -> FunctionSamples {
+        let name = self.unsigned() as usize;
+        let mut function = FunctionSamples::new(&self.names[name]);
+        let location_count = self.unsigned();
+        let callsite_count = self.unsigned();
+        for _ in 0..location_count {
+            let location = self.location();
+            let target_count = self.unsigned();
+            function.add_body_samples(location, self.counter());
+            for _ in 0..target_count {
+                assert_eq!(self.unsigned(), 3, "Wrong histogram type");
+                let callee = self.counter() as usize;
+                let count = self.counter();
+                function.add_call_target_samples(location, &self.names[callee], count);
+            }
+        }
+        for _ in 0..callsite_count {
+            let location = self.location();
+            let callee = self.function_instance();
+            function.total_samples += callee.total_samples;
+            function
+                .callsite_samples
+                .entry(location)
+                .or_default()
+                .insert(callee.name.clone(), callee);
+        }
+        function
+    }
+}
+
+fn read_autofdo(data: &[u8]) -> SampleProfile {
+    let mut reader = AutofdoReader {
+        data,
+        pos: 0,
+        names: vec![],
+    };
+    assert_eq!(reader.unsigned(), 0x67636461, "Wrong magic");
+    assert_eq!(reader.unsigned(), 1, "Wrong version");
+    assert_eq!(reader.unsigned(), 0);
+
+    let end = reader.section(0xaa000000);
+    for _ in 0..reader.unsigned() {
+        let name = reader.string();
+        reader.names.push(name);
+    }
+    assert_eq!(reader.pos, end);
+
+    let mut profile = SampleProfile::new();
+    let end = reader.section(0xac000000);
+    for _ in 0..reader.unsigned() {
+        let head_samples = reader.counter();
+        let mut function = reader.function_instance();
+        function.head_samples = head_samples;
+        profile.functions.insert(function.name.clone(), function);
+    }
+    assert_eq!(reader.pos, end);
+
+    let end = reader.section(0xae000000);
+    assert_eq!(reader.unsigned(), 0, "Unexpected modules");
+    assert_eq!(reader.pos, end);
+    assert_eq!(reader.pos, data.len());
+    profile
+}
+
+#[test]
+fn autofdo_matches_text() {
+    let profile = example_sample_profile();
+    let mut written = vec![];
+    autofdo::write_profile(&mut written, &profile).expect("Error writing profile");
+    assert_eq!(llvm_text(&read_autofdo(&written)), llvm_text(&profile));
+
+    let location = LineLocation {
+        line_offset: 3,
+        discriminator: 1,
+    };
+    assert_eq!(autofdo::location_offset(&location), 0x30001);
+}
+
+#[test]
+fn merge_weighted_sample_profiles() {
+    let profile = example_sample_profile();
+    let mut merged = SampleProfile::new();
+    merged.merge(&profile, 1);
+    merged.merge(&profile, 2);
+
+    let location = |line_offset, discriminator| LineLocation {
+        line_offset,
+        discriminator,
+    };
+    let main = &merged.functions["main"];
+    assert_eq!(
+        main.total_samples,
+        3 * profile.functions["main"].total_samples
+    );
+    assert_eq!(main.head_samples, 6);
+    assert_eq!(main.body_samples[&location(3, 1)], 600);
+    assert_eq!(main.call_targets[&location(3, 1)]["work"], 450);
+    let inlined = &main.callsite_samples[&location(5, 0)]["inlined"];
+    assert_eq!(inlined.body_samples[&location(1, 0)], 90);
+    assert_eq!(inlined.call_targets[&location(2, 0)]["work"], 15);
+    assert_eq!(merged.functions["work"].head_samples, 465);
+
+    // Counts saturate rather than overflowing.
+    merged.merge(&profile, u64::MAX);
+    assert_eq!(merged.functions["main"].total_samples, u64::MAX);
+    // And so do the totals of the summary written for the saturated profile.
+    let summary = ProfileSummary::from_profile(&merged);
+    assert_eq!(summary.total_count, u64::MAX);
+    assert_eq!(summary.max_count, u64::MAX);
+    assert_eq!(summary.detailed_summary[15].min_count, u64::MAX);
+}
+
+#[test]
+fn merge_inputs() {
+    assert_eq!(
+        MergeInput::parse_weighted("3,profiles/a.json").unwrap(),
+        MergeInput {
+            path: "profiles/a.json".into(),
+            weight: 3,
+        }
+    );
+    assert_eq!(
+        MergeInput::parse_weighted(" 3 , profiles/a.json ").unwrap(),
+        MergeInput {
+            path: "profiles/a.json".into(),
+            weight: 3,
+        }
+    );
+    for invalid in ["profiles/a.json", "0,a.json", "x,a.json", "2,", "2, "] {
+        assert!(
+            matches!(
+                MergeInput::parse_weighted(invalid),
+                Err(Error::InvalidMergeInput { .. })
+            ),
+            "{} should be invalid",
+            invalid
+        );
+    }
+
+    // Directories stand for the profiles directly inside them.
+    let dir = tempfile::tempdir().expect("Could not create profile directory");
+    let profiles = dir.path().join("profiles");
+    std::fs::create_dir(&profiles).unwrap();
+    for name in ["b.json.gz", "a.json", "notes.txt"] {
+        std::fs::write(profiles.join(name), "").unwrap();
+    }
+    assert_eq!(
+        merge::inputs_at(&profiles, 2).unwrap(),
+        vec![
+            MergeInput {
+                path: profiles.join("a.json"),
+                weight: 2,
+            },
+            MergeInput {
+                path: profiles.join("b.json.gz"),
+                weight: 2,
+            },
+        ]
+    );
+
+    let manifest = dir.path().join("manifest.txt");
+    std::fs::write(&manifest, "# Profiles to merge\nc.json\n\n5,profiles\n").unwrap();
+    assert_eq!(
+        merge::read_manifest(&manifest).unwrap(),
+        vec![
+            MergeInput::new(dir.path().join("c.json")),
+            MergeInput {
+                path: profiles.join("a.json"),
+                weight: 5,
+            },
+            MergeInput {
+                path: profiles.join("b.json.gz"),
+                weight: 5,
+            },
+        ]
+    );
+
+    std::fs::write(&manifest, "c.json\nheavy,d.json\n").unwrap();
+    match merge::read_manifest(&manifest) {
+        Err(Error::InvalidMergeInput { path, line, input }) => {
+            assert_eq!(path, Some(manifest));
+            assert_eq!(line, Some(2));
+            assert_eq!(input, "heavy,d.json");
+        }
+        other => panic!("Expected an invalid merge input, got {:?}", other),
+    }
+}
+
+#[test]
+fn build_filter_drops_other_builds() {
+    // Make libc_symbol_2, the caller of libc_symbol_3, part of dump_syms rather than libc.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["frameTable"]["nativeSymbol"][14] = json!(1);
+    json["threads"][0]["nativeSymbols"]["libIndex"][1] = json!(0);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    let text = |filter: &SampleFilter| {
+        let (sample_profile, _) =
+            fptc::aggregate_filtered_profile(&profile, filter).expect("Error aggregating");
+        llvm_text(&sample_profile)
+    };
+
+    assert!(text(&SampleFilter::default()).contains("libc_symbol_2:0:0\n 0: 0 libc_symbol_3:1\n"));
+
+    // Breakpad IDs are matched regardless of case.
+    let libc_only = SampleFilter {
+        build: BuildFilter::from_debug_ids(["1629fcf0be5c8860c0e1adf03b0048fb0"]).unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(
+        text(&libc_only),
+        "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n"
+    );
+
+    let dump_syms_only = SampleFilter {
+        build: BuildFilter::from_debug_ids(["5C0A0D51EA1980DF43F203B4525BE9BE0"]).unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(text(&dump_syms_only), "");
+
+    // Samples whose leaf is in a system library keep the calls between the build's own functions.
+    json["threads"][0]["frameTable"]["nativeSymbol"][13] = json!(1);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    let (sample_profile, discarded) =
+        fptc::aggregate_filtered_profile(&profile, &dump_syms_only).expect("Error aggregating");
+    assert_eq!(
+        llvm_text(&sample_profile),
+        "libc_symbol_2:0:0\n 0: 0 libc_symbol_2:1\n"
+    );
+    assert_eq!(discarded.total(), 0);
+
+    // Samples in another build of one of the filter's libraries are discarded.
+    json["libs"][0]["debugName"] = json!("libc.so.6");
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let (sample_profile, discarded) =
+        fptc::aggregate_filtered_profile(&profile, &dump_syms_only).expect("Error aggregating");
+    assert_eq!(llvm_text(&sample_profile), "");
+    assert_eq!(discarded.other_builds, 2);
+}
+
+#[tokio::test]
+async fn merge_profiles_with_weights() {
+    let dir = tempfile::tempdir().expect("Could not create profile directory");
+    let profile = dir.path().join("profile.json");
+    std::fs::write(&profile, profile_without_js_json().to_string()).unwrap();
+    let invalid = dir.path().join("invalid.json");
+    std::fs::write(&invalid, "{\"meta\": ").unwrap();
+
+    let inputs = vec![
+        MergeInput::new(&profile),
+        MergeInput::new(&invalid),
+        MergeInput {
+            path: profile.clone(),
+            weight: 2,
+        },
+    ];
+    // Nothing is allowed, so nothing is fetched from the (unreachable) symbol server.
+    let server = SymbolServer::new("http://127.0.0.1:9", dir.path()).unwrap();
+    let allowlist = LibraryAllowlist::new(Vec::<String>::new());
+    let (merged, report) =
+        merge::merge_profiles(&inputs, &server, &allowlist, &SampleFilter::default())
+            .await
+            .unwrap();
+
+    assert_eq!(report.merged, 2);
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].0, invalid);
+    assert!(report.empty.is_empty());
+    assert!(report.warnings.is_empty());
+    assert_eq!(
+        llvm_text(&merged),
+        "libc_symbol_1:3:0\n 0: 3\nlibc_symbol_3:3:0\n 0: 3\n"
+    );
+
+    // Profiles whose samples are all filtered out aren't counted as merged.
+    let dump_syms_only = SampleFilter {
+        build: BuildFilter::from_debug_ids(["5C0A0D51EA1980DF43F203B4525BE9BE0"]).unwrap(),
+        ..Default::default()
+    };
+    let (merged, report) = merge::merge_profiles(&inputs, &server, &allowlist, &dump_syms_only)
+        .await
+        .unwrap();
+    assert_eq!(report.merged, 0);
+    assert_eq!(report.empty, vec![profile.clone(), profile]);
+    assert_eq!(llvm_text(&merged), "");
+}
+
+#[tokio::test]
+async fn merge_refuses_mixed_builds() {
+    let dir = tempfile::tempdir().expect("Could not create profile directory");
+    let profile = dir.path().join("profile.json");
+    std::fs::write(&profile, profile_without_js_json().to_string()).unwrap();
+    let mut json = profile_without_js_json();
+    json["libs"][1]["breakpadId"] = json!("00000000000000000000000000000000A");
+    let other_build = dir.path().join("other_build.json");
+    std::fs::write(&other_build, json.to_string()).unwrap();
+
+    let inputs = vec![MergeInput::new(&profile), MergeInput::new(&other_build)];
+    let server = SymbolServer::new("http://127.0.0.1:9", dir.path()).unwrap();
+    let allowlist = LibraryAllowlist::new(["libc.so.6"]);
+    match merge::merge_profiles(&inputs, &server, &allowlist, &SampleFilter::default()).await {
+        Err(Error::MixedBuilds {
+            path,
+            library,
+            breakpad_ids,
+        }) => {
+            assert_eq!(path, other_build);
+            assert_eq!(library, "libc.so.6");
+            assert_eq!(
+                breakpad_ids,
+                (
+                    "1629FCF0BE5C8860C0E1ADF03B0048FB0".to_string(),
+                    "00000000000000000000000000000000A".to_string()
+                )
+            );
+        }
+        other => panic!(
+            "Expected mixed builds to be refused, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+
+    // Libraries outside the allowlist can differ, as they aren't optimised.
+    let allowlist = LibraryAllowlist::new(["dump_syms"]);
+    let (_, report) = merge::merge_profiles(&inputs, &server, &allowlist, &SampleFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(report.merged, 2);
+
+    // With a build to keep, the samples of other builds are left out instead.
+    let libc_only = SampleFilter {
+        build: BuildFilter::from_debug_ids(["1629FCF0BE5C8860C0E1ADF03B0048FB0"]).unwrap(),
+        ..Default::default()
+    };
+    let allowlist = LibraryAllowlist::new(["libc.so.6"]);
+    let (merged, report) = merge::merge_profiles(&inputs, &server, &allowlist, &libc_only)
+        .await
+        .unwrap();
+    assert_eq!(report.merged, 1);
+    assert_eq!(report.empty, vec![other_build]);
+    assert_eq!(
+        llvm_text(&merged),
+        "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n"
+    );
+}
+
+#[tokio::test]
+async fn build_filter_ids_and_discarded_samples() {
+    // Debug IDs can be given in breakpad's form, or as a UUID.
+    let breakpad = libraries::parse_debug_id("1629FCF0BE5C8860C0E1ADF03B0048FB0").unwrap();
+    let uuid = libraries::parse_debug_id("1629fcf0-be5c-8860-c0e1-adf03b0048fb").unwrap();
+    assert_eq!(breakpad, uuid);
+    assert!(matches!(
+        BuildFilter::from_debug_ids(["libxul.so"]),
+        Err(Error::InvalidBuildId(id)) if id == "libxul.so"
+    ));
+
+    let libc = lib(
+        "libc.so.6",
+        "libc.so.6",
+        "1629FCF0BE5C8860C0E1ADF03B0048FB0",
+        None,
+    );
+    let mut build = BuildFilter::default();
+    assert!(build.matches(&libc));
+    build.add_debug_id(DebugId::from_breakpad("5C0A0D51EA1980DF43F203B4525BE9BE0").unwrap());
+    assert!(!build.matches(&libc));
+
+    // Libraries also match on their code ID, such as an ELF build ID.
+    let mut libc_with_code_id = libc.clone();
+    libc_with_code_id.codeId = Some("F0FC29165CBE6088C0E1ADF03B0048FBECBC003A".to_string());
+    build.add_code_id(CodeId::new(
+        "f0fc29165cbe6088c0e1adf03b0048fbecbc003a".to_string(),
+    ));
+    assert!(!build.matches(&libc));
+    assert!(build.matches(&libc_with_code_id));
+
+    // Binaries that can't be read can't give us a build.
+    let dir = tempfile::tempdir().expect("Could not create binary directory");
+    assert!(BuildFilter::default()
+        .add_binary(&dir.path().join("missing.so"))
+        .await
+        .is_err());
+
+    // Samples from other builds of the filter's libraries are counted as they are discarded, but samples in other libraries aren't from other builds.
+    let mut json = profile_without_js_json();
+    let filter = SampleFilter {
+        build: BuildFilter::from_debug_ids(["5C0A0D51EA1980DF43F203B4525BE9BE0"]).unwrap(),
+        ..Default::default()
+    };
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    let (samples, discarded) = fptc::transposed::transpose_filtered_samples(&profile, &filter);
+    assert_eq!(samples.len(), 2);
+    assert_eq!(discarded.total(), 0);
+    json["libs"][0]["debugName"] = json!("libc.so.6");
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let (samples, discarded) = fptc::transposed::transpose_filtered_samples(&profile, &filter);
+    assert!(samples.is_empty());
+    assert_eq!(discarded.other_builds, 2);
+    assert_eq!(discarded.total(), 2);
+    let (samples, discarded) =
+        fptc::transposed::transpose_filtered_samples(&profile, &SampleFilter::default());
+    assert_eq!(samples.len(), 2);
+    assert_eq!(discarded.total(), 0);
+}
+
+#[test]
+fn samples_are_counted_by_weight() {
+    let aggregate = |json: serde_json::Value, weights: WeightPolicy| {
+        let profile: Profile = serde_json::from_value(json).unwrap();
+        let filter = SampleFilter {
+            weights,
+            ..Default::default()
+        };
+        fptc::aggregate_filtered_profile(&profile, &filter)
+            .map(|(sample_profile, discarded)| (llvm_text(&sample_profile), discarded))
+    };
+
+    let mut json = profile_without_js_json();
+    json["threads"][0]["samples"]["weight"] = json!([1, 3, 0, 2]);
+    // Samples with no weight are left out altogether.
+    let (text, discarded) = aggregate(json.clone(), WeightPolicy::Reject).unwrap();
+    assert_eq!(text, "libc_symbol_1:3:0\n 0: 3\nlibc_symbol_3:2:0\n 0: 2\n");
+    assert_eq!(discarded.total(), 0);
+
+    // Tracing durations are rejected unless they are converted into sampling intervals.
+    json["meta"]["interval"] = json!(0.5);
+    json["threads"][0]["samples"]["weightType"] = json!("tracing-ms");
+    match aggregate(json.clone(), WeightPolicy::Reject) {
+        Err(Error::UnsupportedWeightType {
+            thread: 0,
+            weight_type: WeightType::TracingMs,
+        }) => {}
+        other => panic!("Expected an unsupported weight type, got {:?}", other),
+    }
+    let (text, discarded) = aggregate(json.clone(), WeightPolicy::Skip).unwrap();
+    assert_eq!(text, "");
+    // Discarded samples are counted in the same units as the samples that converting them would keep.
+    assert_eq!(discarded.unsupported_weights, 10);
+    let (text, _) = aggregate(json.clone(), WeightPolicy::Convert).unwrap();
+    assert_eq!(text, "libc_symbol_1:6:0\n 0: 6\nlibc_symbol_3:4:0\n 0: 4\n");
+
+    // Traced durations are usually fractional, and are rounded once they are in sampling intervals.
+    let traced = r#"{"stack": [null, 6, 11, 15], "time": [0.0, 1.0, 2.0, 3.0], "weight": [0.2, 1.3, 0.1, 0.76], "weightType": "tracing-ms", "length": 4}"#;
+    let samples: SamplesTable = serde_json::from_str(traced).expect("Error parsing samples");
+    assert_eq!(samples.weight.as_ref().unwrap()[1], Weight::Float(1.3));
+    assert_json_eq!(
+        serde_json::to_value(&samples).unwrap()["weight"],
+        json!([0.2, 1.3, 0.1, 0.76])
+    );
+    json["threads"][0]["samples"] = serde_json::from_str(traced).unwrap();
+    let (text, _) = aggregate(json.clone(), WeightPolicy::Convert).unwrap();
+    assert_eq!(text, "libc_symbol_1:3:0\n 0: 3\nlibc_symbol_3:2:0\n 0: 2\n");
+
+    // Allocated bytes count as a single sample each, however many bytes they are.
+    json["threads"][0]["samples"]["weightType"] = json!("bytes");
+    json["threads"][0]["samples"]["weight"] = json!([1, 4096, 0, 16]);
+    let (text, _) = aggregate(json, WeightPolicy::Convert).unwrap();
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n");
+}
+
+#[test]
+fn cpu_weighting_discounts_idle_samples() {
+    let mut json = profile_without_js_json();
+    // The sample of libc_symbol_1 was busy throughout, the one without a native symbol was idle, and the one of libc_symbol_3 was busy for half of its interval.
+    json["threads"][0]["samples"]["threadCPUDelta"] = json!([0, 1000, 20, 500]);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(
+        fptc::transposed::cpu_fractions(&profile.threads[0].samples, &profile.meta),
+        vec![Some(0.0), Some(1.0), Some(0.02), Some(0.5)]
+    );
+    let aggregate = |profile: &Profile, cpu: CpuWeighting| {
+        let filter = SampleFilter {
+            cpu,
+            ..Default::default()
+        };
+        let (sample_profile, discarded) =
+            fptc::aggregate_filtered_profile(profile, &filter).expect("Error aggregating");
+        (llvm_text(&sample_profile), discarded)
+    };
+
+    let (text, discarded) = aggregate(&profile, CpuWeighting::WallClock);
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n");
+    assert_eq!(discarded.total(), 0);
+
+    // Samples without a stack aren't counted as discarded.
+    let (text, discarded) = aggregate(&profile, CpuWeighting::DropIdle { threshold: 0.05 });
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n");
+    assert_eq!(discarded.idle, 1);
+    let (text, discarded) = aggregate(&profile, CpuWeighting::DropIdle { threshold: 0.6 });
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\n");
+    assert_eq!(discarded.idle, 2);
+
+    // A busy sample keeps its weight, and the half of a sample left over from libc_symbol_3 isn't enough to count.
+    let (text, discarded) = aggregate(&profile, CpuWeighting::Scale);
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\n");
+    assert_eq!(discarded.idle, 0);
+
+    // The CPU usage of a sample without a stack isn't carried on to libc_symbol_3.
+    let mut busy_stackless = json.clone();
+    busy_stackless["threads"][0]["samples"]["threadCPUDelta"] = json!([500, 1000, 20, 500]);
+    let busy_stackless: Profile = serde_json::from_value(busy_stackless).unwrap();
+    let (text, _) = aggregate(&busy_stackless, CpuWeighting::Scale);
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\n");
+
+    // Cycles are compared with the busiest sample, and samples without a delta count as busy.
+    json["meta"]["sampleUnits"]["threadCPUDelta"] = json!("variable CPU cycles");
+    json["threads"][0]["samples"]["threadCPUDelta"] = json!([0, 4000, 80, null]);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(
+        fptc::transposed::cpu_fractions(&profile.threads[0].samples, &profile.meta),
+        vec![Some(0.0), Some(1.0), Some(0.02), None]
+    );
+    let (text, _) = aggregate(&profile, CpuWeighting::Scale);
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n");
+
+    // Nanosecond deltas of samples seconds apart don't fit in 32 bits.
+    json["meta"]["sampleUnits"]["threadCPUDelta"] = json!("ns");
+    json["threads"][0]["samples"]["time"] = json!([0.0, 3000.0, 6000.0, 9000.0]);
+    json["threads"][0]["samples"]["threadCPUDelta"] =
+        json!([0, 3_000_000_000_i64, 0, 1_500_000_000_i64]);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(
+        fptc::transposed::cpu_fractions(&profile.threads[0].samples, &profile.meta),
+        vec![Some(0.0), Some(1.0), Some(0.0), Some(0.5)]
+    );
+
+    // Without units, the deltas can't be interpreted.
+    json["meta"].as_object_mut().unwrap().remove("sampleUnits");
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let (text, discarded) = aggregate(&profile, CpuWeighting::DropIdle { threshold: 0.05 });
+    assert_eq!(text, "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n");
+    assert_eq!(discarded.idle, 0);
+}
+
+#[test]
+fn thread_selectors() {
+    let mut json = profile_without_js_json();
+    let mut renderer = json["threads"][0].clone();
+    renderer["name"] = json!("Renderer");
+    renderer["processType"] = json!("tab");
+    renderer["processName"] = json!("Isolated Web Content");
+    renderer["isMainThread"] = json!(false);
+    renderer["pid"] = json!("456");
+    json["threads"].as_array_mut().unwrap().push(renderer);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let (main, renderer) = (&profile.threads[0], &profile.threads[1]);
+
+    let selector = ThreadSelector::parse("name=Renderer,process-type=tab").unwrap();
+    assert_eq!(
+        selector,
+        ThreadSelector::name("Renderer").and(ThreadSelector::ProcessType(ProcessType::Tab))
+    );
+    assert!(selector.matches(renderer));
+    assert!(!selector.matches(main));
+    let selector = ThreadSelector::parse("main-thread").unwrap();
+    assert!(selector.matches(main) && !selector.matches(renderer));
+    let selector = ThreadSelector::parse("main-thread=false, pid=456").unwrap();
+    assert!(!selector.matches(main) && selector.matches(renderer));
+    let selector = ThreadSelector::parse("!process-name=test").unwrap();
+    assert_eq!(selector, !ThreadSelector::process_name("test"));
+    assert!(!selector.matches(main) && selector.matches(renderer));
+    let selector = ThreadSelector::name("test").or(ThreadSelector::Pid("456".to_string()));
+    assert!(selector.matches(main) && selector.matches(renderer));
+    for invalid in [
+        "name",
+        "colour=red",
+        "process-type=tabs",
+        "main-thread=yes",
+        "",
+    ] {
+        assert!(
+            matches!(
+                ThreadSelector::parse(invalid),
+                Err(Error::InvalidThreadSelector(_))
+            ),
+            "{:?} should be invalid",
+            invalid
+        );
+    }
+
+    let aggregate = |threads: ThreadFilter| {
+        let filter = SampleFilter {
+            threads,
+            ..Default::default()
+        };
+        let (sample_profile, discarded) =
+            fptc::aggregate_filtered_profile(&profile, &filter).expect("Error aggregating");
+        (llvm_text(&sample_profile), discarded.other_threads)
+    };
+    assert_eq!(
+        aggregate(ThreadFilter::default()),
+        (
+            "libc_symbol_1:2:0\n 0: 2\nlibc_symbol_3:2:0\n 0: 2\n".to_string(),
+            0
+        )
+    );
+    let mut content_only = ThreadFilter::default();
+    content_only.include(ThreadSelector::ProcessType(ProcessType::Tab));
+    assert_eq!(
+        aggregate(content_only),
+        (
+            "libc_symbol_1:1:0\n 0: 1\nlibc_symbol_3:1:0\n 0: 1\n".to_string(),
+            3
+        )
+    );
+    let mut excluded = ThreadFilter::default();
+    excluded.include(ThreadSelector::MainThread.or(ThreadSelector::name("Renderer")));
+    excluded.exclude(ThreadSelector::name("Renderer"));
+    excluded.exclude(ThreadSelector::MainThread);
+    assert_eq!(aggregate(excluded), (String::new(), 6));
+}
+
+#[test]
+fn time_ranges() {
+    assert_eq!(
+        TimeRange::parse("1500..", TimeOrigin::ProfileStart).unwrap(),
+        TimeRange {
+            start: Some(1500.0),
+            end: None,
+            origin: TimeOrigin::ProfileStart,
+        }
+    );
+    assert_eq!(
+        TimeRange::from_committed_ranges("1843m1500~1900123u200000").unwrap(),
+        TimeRange {
+            start: Some(1900.123),
+            end: Some(2100.123),
+            origin: TimeOrigin::ProfileStart,
+        }
+    );
+    for invalid in ["1500", "2..1", "a..b"] {
+        assert!(matches!(
+            TimeRange::parse(invalid, TimeOrigin::ProfileStart),
+            Err(Error::InvalidTimeRange(_))
+        ));
+    }
+    for invalid in ["1500", "12x3", "m5", "1m"] {
+        assert!(matches!(
+            TimeRange::from_committed_ranges(invalid),
+            Err(Error::InvalidTimeRange(_))
+        ));
+    }
+
+    // The samples of libc_symbol_1 and libc_symbol_3 are taken 1ms and 3ms after the first sample.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["samples"]["time"] = json!([10.0, 11.0, 12.0, 13.0]);
+    json["threads"][0]["registerTime"] = json!(10.5);
+    json["threads"][0]["unregisterTime"] = json!(12.0);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    let aggregate = |ranges: Vec<TimeRange>, clip_to_thread_lifetime: bool| {
+        let filter = SampleFilter {
+            ranges,
+            clip_to_thread_lifetime,
+            ..Default::default()
+        };
+        let (sample_profile, discarded) =
+            fptc::aggregate_filtered_profile(&profile, &filter).expect("Error aggregating");
+        (llvm_text(&sample_profile), discarded.out_of_range)
+    };
+    let symbol_1 = "libc_symbol_1:1:0\n 0: 1\n".to_string();
+    let symbol_3 = "libc_symbol_3:1:0\n 0: 1\n".to_string();
+
+    let relative = TimeRange::parse("1..2", TimeOrigin::ProfileStart).unwrap();
+    assert_eq!(aggregate(vec![relative], false), (symbol_1.clone(), 2));
+    let absolute = TimeRange::parse("1636162232640..", TimeOrigin::UnixEpoch).unwrap();
+    assert_eq!(aggregate(vec![absolute], false), (symbol_3.clone(), 2));
+    let committed = TimeRange::from_committed_ranges("0m10~3000u1000").unwrap();
+    assert_eq!(aggregate(vec![committed], false), (symbol_3, 2));
+    assert_eq!(
+        aggregate(vec![relative, committed], false),
+        (String::new(), 3)
+    );
+    assert_eq!(aggregate(vec![], true), (symbol_1.clone(), 2));
+
+    // Profiles that record when profiling started are measured from then, rather than from their first sample.
+    let mut json = profile_without_js_json();
+    json["threads"][0]["samples"]["time"] = json!([10.0, 11.0, 12.0, 13.0]);
+    json["meta"]["profilingStartTime"] = json!(9.0);
+    json["meta"]["profilingEndTime"] = json!(14.0);
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    assert_eq!(fptc::time_range::profile_zero(&profile), 9.0);
+    let aggregate = |range: TimeRange| {
+        let filter = SampleFilter {
+            ranges: vec![range],
+            ..Default::default()
+        };
+        let (sample_profile, discarded) =
+            fptc::aggregate_filtered_profile(&profile, &filter).expect("Error aggregating");
+        (llvm_text(&sample_profile), discarded.out_of_range)
+    };
+    let relative = TimeRange::parse("2..3", TimeOrigin::ProfileStart).unwrap();
+    assert_eq!(aggregate(relative), (symbol_1, 2));
+    let committed = TimeRange::from_committed_ranges("4m1").unwrap();
+    assert_eq!(
+        aggregate(committed),
+        ("libc_symbol_3:1:0\n 0: 1\n".to_string(), 2)
+    );
+}
+
+#[test]
+fn paused_ranges_are_excluded() {
+    let mut json = profile_without_js_json();
+    json["threads"][0]["pausedRanges"] =
+        json!([{"startTime": 0.5, "endTime": 1.5, "reason": "profiler-paused"}]);
+    json["meta"]["pausedRanges"] =
+        json!([{"startTime": 2.5, "endTime": null, "reason": "collecting"}]);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(
+        profile.threads[0].pausedRanges,
+        vec![PausedRange {
+            startTime: Some(0.5),
+            endTime: Some(1.5),
+            reason: PausedReason::ProfilerPaused,
+        }]
+    );
+    let meta_range = profile.meta.pausedRanges.as_ref().unwrap()[0];
+    assert_eq!(meta_range.reason, PausedReason::Collecting);
+    assert!(meta_range.contains(2.5) && meta_range.contains(1e9) && !meta_range.contains(2.0));
+    assert_json_eq!(
+        serde_json::to_value(&profile.meta).unwrap()["pausedRanges"],
+        json["meta"]["pausedRanges"]
+    );
+
+    // The sample of libc_symbol_1 is taken while the thread is paused, and the one of libc_symbol_3 while the whole profile is.
+    let (sample_profile, discarded) =
+        fptc::aggregate_filtered_profile(&profile, &SampleFilter::default())
+            .expect("Error aggregating");
+    assert_eq!(llvm_text(&sample_profile), "");
+    assert_eq!(discarded.paused, 2);
+    assert_eq!(discarded.total(), 2);
+
+    // Profiles from before paused ranges were recorded for each thread have none.
+    json["threads"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("pausedRanges");
+    let profile: Profile = serde_json::from_value(json).unwrap();
+    assert!(profile.threads[0].pausedRanges.is_empty());
+}
+
+#[test]
+fn demangled_names_are_left_out() {
+    assert!(is_assembler_name("_ZN7nsINode14GetTextContentER9nsAString"));
+    assert!(is_assembler_name(
+        "?GetTextContent@nsINode@@QEAAXAEAVnsAString@@@Z"
+    ));
+    assert!(is_assembler_name("__poll"));
+    assert!(is_assembler_name("helper.__uniq.123"));
+    assert!(!is_assembler_name("nsINode::GetTextContent(nsAString&)"));
+    assert!(!is_assembler_name("mozilla::Maybe<int>::value() const"));
+    assert!(!is_assembler_name(""));
+
+    // Mozilla's Breakpad symbols have demangled names for C++ functions.
+    let symbols = LIBC_BREAKPAD_SYMBOLS
+        .replace(
+            "_IO_new_file_write",
+            "_ZN7nsINode14GetTextContentER9nsAString",
+        )
+        .replace("new_do_write", "mozilla::Write(int, void const*)")
+        .replace("__poll", "nsPollWrapper::Poll(int)");
+    let profile: Profile =
+        serde_json::from_value(profile_without_js_json()).expect("Error parsing json");
+    let libc = BreakpadSymbols::parse(symbols.as_bytes()).expect("Invalid symbols");
+    let profile = symbolicate_profile(profile, &[None, Some(SymbolSource::Breakpad(libc))]);
+    let mut sample_profile = fptc::aggregate_profile(&profile).expect("Error aggregating");
+
+    // Demangled functions are dropped, and the samples of demangled inlined callees are counted at their callsite.
+    let removed = sample_profile.remove_demangled_names();
+    assert_eq!(
+        removed.names.into_iter().collect::<Vec<_>>(),
+        vec![
+            "mozilla::Write(int, void const*)",
+            "nsPollWrapper::Poll(int)"
+        ]
+    );
+    // Only nsPollWrapper::Poll's sample is lost, as new_do_write's is counted in the function that it was inlined into.
+    assert_eq!(removed.samples, 1);
+    assert_eq!(
+        llvm_text(&sample_profile),
+        "_ZN7nsINode14GetTextContentER9nsAString:1:0\n 5: 1\n"
+    );
+
+    // None of the writers write the demangled names out.
+    let mut written = vec![];
+    extbinary::write_profile(
+        &mut written,
+        &sample_profile,
+        &extbinary::Options::default(),
+    )
+    .expect("Error writing profile");
+    autofdo::write_profile(&mut written, &sample_profile).expect("Error writing profile");
+    let written = String::from_utf8_lossy(&written);
+    assert!(written.contains("_ZN7nsINode14GetTextContentER9nsAString"));
+    assert!(!written.contains("mozilla::Write"));
+    assert!(!written.contains("nsPollWrapper::Poll"));
+}
+
+#[tokio::test]
+async fn symbolicate_rejects_inconsistent_tables() {
+    // A frame whose native symbol is past the end of the native symbol table would make symbolication index out of bounds.
+    let mut broken = profile_without_js_json();
+    broken["threads"][0]["frameTable"]["nativeSymbol"][6] = json!(99);
+    let profile: Profile = serde_json::from_value(broken).unwrap();
+    let dir = tempfile::tempdir().expect("Could not create cache directory");
+    let server = SymbolServer::new("http://127.0.0.1:9", dir.path()).unwrap();
+    let allowlist = LibraryAllowlist::new(Vec::<String>::new());
+    let (symbolicated, errors) = fptc::symbolicate(profile, &server, &allowlist).await;
+    assert!(matches!(errors.as_slice(), [Error::InconsistentTables(_)]));
+    assert_eq!(symbolicated.meta.symbolicated, Some(false));
+    assert_eq!(symbolicated.threads[0].frameTable.nativeSymbol[6], Some(99));
+}